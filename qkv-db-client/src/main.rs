@@ -7,7 +7,7 @@ fn main() {
     if let Some(path) = args.get(1) {
         let mut stream = TcpStream::connect("127.0.0.1:7878").unwrap();
         let buf = std::fs::read(path).unwrap();
-        stream.write(&(buf.len() as u32).to_le_bytes()).unwrap();
+        stream.write_all(&(buf.len() as u32).to_le_bytes()).unwrap();
         stream.write_all(&buf).unwrap();
         stream.flush().unwrap();
 
@@ -27,7 +27,7 @@ fn main() {
         print!("> ");
         std::io::stdout().flush().unwrap();
         stdin().read_line(&mut buf).unwrap();
-        stream.write(&(buf.as_bytes().len() as u32).to_le_bytes()).unwrap();
+        stream.write_all(&(buf.len() as u32).to_le_bytes()).unwrap();
         stream.write_all(buf.as_bytes()).unwrap();
         stream.flush().unwrap();
 
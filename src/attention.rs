@@ -0,0 +1,910 @@
+use ndarray::linalg::general_mat_mul;
+use ndarray::{s, Array1, Array2, ArrayView1, ArrayView2, Axis, Zip};
+use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuildError, ThreadPoolBuilder};
+use serde::{Deserialize, Serialize};
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::mem::size_of;
+
+/// Result of a scan over a part of the bucket, which can be combined with results over other parts.
+pub trait Partial: Clone + Send + Sync {
+    /// Returns a result of the same shape that has not seen any entry.
+    fn empty(&self) -> Self;
+
+    /// Merges result over another disjoint set of entries into this one.
+    fn merge(&mut self, other: Self);
+}
+
+/// Softmax attention over a part of the bucket.
+/// Partials over disjoint sets of entries can be merged in any order, weighting each by its log-sum-exp.
+///
+/// With multiple heads every query is split into `heads` parts attending independently, each over its own part of the values.
+/// Softmax state is then kept per query and head, heads of a query being adjacent, and "query" below means such a pair.
+#[derive(Debug, Clone)]
+pub struct PartialAttention {
+    heads: usize,
+    /// Maximum logit seen so far for each query.
+    max: Array1<f32>,
+    /// Sum of `exp(logit - max)` for each query.
+    sum: Array1<f32>,
+    /// Sum of `exp(logit - max) * (logit - max)` for each query, which gives entropy of the weights.
+    shifted_logits: Array1<f32>,
+    /// Values weighted by `exp(logit - max)`, one row per query.
+    acc: Array2<f32>,
+    /// Entries with the highest logits for each query, kept without values. None are kept unless requested.
+    contributors: Candidates,
+}
+
+/// Entry that contributed to the attention of a query.
+#[derive(Debug, Copy, Clone)]
+pub struct Contribution {
+    pub row: usize,
+    pub logit: f32,
+    /// Softmax weight of the entry among all entries the query attended over.
+    pub weight: f32,
+}
+
+impl PartialAttention {
+    /// Attention of `queries` split into `heads` heads over values of `value_size` elements, which are split among the heads evenly.
+    pub fn new(queries: usize, heads: usize, value_size: usize) -> Self {
+        let rows = queries * heads;
+        Self {
+            heads,
+            max: Array1::from_elem(rows, f32::NEG_INFINITY),
+            sum: Array1::zeros(rows),
+            shifted_logits: Array1::zeros(rows),
+            acc: Array2::zeros((rows, value_size / heads)),
+            contributors: Candidates::new(rows, 0),
+        }
+    }
+
+    /// Keeps up to `limit` entries with the highest logits for each query, see [`PartialAttention::contributions`].
+    pub fn with_contributors(mut self, limit: usize) -> Self {
+        self.contributors = Candidates::new(self.max.len(), limit);
+        self
+    }
+
+    /// Accumulates values `v` of entries `rows` weighted by `logits`, which have one row per query and one column per entry.
+    /// Every head attends over its part of `v`. Entries with logit of negative infinity are skipped.
+    pub fn attend_logits(&mut self, mut logits: Array2<f32>, v: ArrayView2<f32>, rows: impl Iterator<Item = usize> + Clone) {
+        if self.contributors.limit > 0 {
+            for (query, logits) in logits.rows().into_iter().enumerate() {
+                for (&logit, row) in logits.iter().zip(rows.clone()) {
+                    if self.contributors.qualifies(query, logit) {
+                        self.contributors.offer(query, Candidate { logit, row, value: Array1::zeros(0) });
+                    }
+                }
+            }
+        }
+        let max = logits.fold_axis(Axis(1), f32::NEG_INFINITY, |a, b| a.max(*b));
+        let mut shifted_logits = Array1::zeros(max.len());
+        Zip::from(logits.rows_mut()).and(&max).and(&mut shifted_logits).for_each(|mut row, &m, shifted| {
+            if m == f32::NEG_INFINITY {
+                row.fill(0.);
+            } else {
+                row.mapv_inplace(|x| {
+                    let weight = (x - m).exp();
+                    // Masked entries have zero weight and must not turn the sum into NaN.
+                    if weight > 0. {
+                        *shifted += weight * (x - m);
+                    }
+                    weight
+                });
+            }
+        });
+        let sum = logits.sum_axis(Axis(1));
+        let acc = if self.heads == 1 {
+            logits.dot(&v)
+        } else {
+            let width = self.acc.ncols();
+            let mut acc = Array2::zeros(self.acc.raw_dim());
+            for head in 0..self.heads {
+                let heads = self.heads as isize;
+                let v = v.slice(s![.., head * width..(head + 1) * width]);
+                general_mat_mul(1., &logits.slice(s![head..;heads, ..]), &v, 0., &mut acc.slice_mut(s![head..;heads, ..]));
+            }
+            acc
+        };
+        self.merge(PartialAttention { heads: self.heads, max, sum, shifted_logits, acc, contributors: self.contributors.empty() });
+    }
+
+    /// Accumulates a single entry `row` with value `v` and logit `logit` for query number `query`.
+    /// `v` is the whole value of the entry, the head of the query attends over its part of it.
+    pub fn add(&mut self, query: usize, logit: f32, v: ArrayView1<f32>, row: usize) {
+        if logit == f32::NEG_INFINITY {
+            return;
+        }
+        let width = self.acc.ncols();
+        let head = query % self.heads;
+        let v = v.slice(s![head * width..(head + 1) * width]);
+        if self.contributors.qualifies(query, logit) {
+            self.contributors.offer(query, Candidate { logit, row, value: Array1::zeros(0) });
+        }
+        let max = &mut self.max[query];
+        let sum = &mut self.sum[query];
+        let shifted = &mut self.shifted_logits[query];
+        let mut acc = self.acc.row_mut(query);
+        if logit > *max {
+            let scale = (*max - logit).exp();
+            *shifted = if *sum > 0. { scale * (*shifted + (*max - logit) * *sum) } else { 0. };
+            *sum = *sum * scale + 1.;
+            acc.zip_mut_with(&v, |a, b| *a = *a * scale + b);
+            *max = logit;
+        } else {
+            let weight = (logit - *max).exp();
+            if weight > 0. {
+                *shifted += weight * (logit - *max);
+            }
+            *sum += weight;
+            acc.scaled_add(weight, &v);
+        }
+    }
+
+    /// Entries with the highest logits for each query and head along with their weights, best first.
+    pub fn contributions(&self) -> Vec<Vec<Contribution>> {
+        self.contributors.heaps.iter().enumerate().map(|(query, heap)| {
+            let mut contributions: Vec<Contribution> = heap.iter().map(|c| Contribution {
+                row: c.0.row,
+                logit: c.0.logit,
+                weight: (c.0.logit - self.max[query]).exp() / self.sum[query],
+            }).collect();
+            contributions.sort_unstable_by(|a, b| b.logit.total_cmp(&a.logit).then(a.row.cmp(&b.row)));
+            contributions
+        }).collect()
+    }
+
+    /// Entropy of the attention weights of each query and head in nats, zero for those that did not see any entry.
+    pub fn entropy(&self) -> Array1<f32> {
+        Zip::from(&self.sum).and(&self.shifted_logits).map_collect(|&sum, &shifted| {
+            if sum > 0. { sum.ln() - shifted / sum } else { 0. }
+        })
+    }
+
+    /// Normalizes accumulated values, producing one attended vector per query, which concatenates the vectors of its heads.
+    /// Heads that did not see any entry get a zero vector.
+    pub fn finish(self) -> Array2<f32> {
+        let mut acc = self.acc;
+        Zip::from(acc.rows_mut()).and(&self.sum).for_each(|mut row, &sum| {
+            if sum > 0. {
+                row.mapv_inplace(|x| x / sum);
+            }
+        });
+        let shape = (acc.nrows() / self.heads, acc.ncols() * self.heads);
+        acc.into_shape(shape).expect("Accumulated values are contiguous")
+    }
+}
+
+impl Partial for PartialAttention {
+    fn empty(&self) -> Self {
+        Self::new(self.max.len() / self.heads, self.heads, self.acc.ncols() * self.heads).with_contributors(self.contributors.limit)
+    }
+
+    fn merge(&mut self, other: PartialAttention) {
+        for query in 0..self.max.len() {
+            let other_max = other.max[query];
+            if other_max == f32::NEG_INFINITY {
+                continue;
+            }
+            let (max, sum, shifted) = (self.max[query], self.sum[query], self.shifted_logits[query]);
+            let new_max = max.max(other_max);
+            let scale = (max - new_max).exp();
+            let other_scale = (other_max - new_max).exp();
+            // Shifting logits by the difference of maxima adds that difference times the sum.
+            let rebase = |shifted: f32, max: f32, sum: f32| if sum > 0. { shifted + (max - new_max) * sum } else { 0. };
+            self.shifted_logits[query] = rebase(shifted, max, sum) * scale + rebase(other.shifted_logits[query], other_max, other.sum[query]) * other_scale;
+            self.sum[query] = sum * scale + other.sum[query] * other_scale;
+            self.acc.row_mut(query).zip_mut_with(&other.acc.row(query), |a, b| *a = *a * scale + b * other_scale);
+            self.max[query] = new_max;
+        }
+        for (query, candidate) in other.contributors.into_entries() {
+            if self.contributors.qualifies(query, candidate.logit) {
+                self.contributors.offer(query, candidate);
+            }
+        }
+    }
+}
+
+/// Entry held back by [`RescoringAttention`] or [`TopKAttention`] until the end of the scan.
+#[derive(Debug, Clone)]
+struct Candidate {
+    logit: f32,
+    row: usize,
+    value: Array1<f32>,
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.logit.total_cmp(&other.logit).then(self.row.cmp(&other.row))
+    }
+}
+
+/// Up to `limit` entries with the highest logits for every query.
+#[derive(Debug, Clone)]
+struct Candidates {
+    /// Min-heap of held back entries for each query.
+    heaps: Vec<BinaryHeap<Reverse<Candidate>>>,
+    limit: usize,
+}
+
+impl Candidates {
+    fn new(queries: usize, limit: usize) -> Self {
+        Self { heaps: vec![BinaryHeap::with_capacity(limit + 1); queries], limit }
+    }
+
+    /// Returns candidates of the same shape that hold nothing.
+    fn empty(&self) -> Self {
+        Self::new(self.heaps.len(), self.limit)
+    }
+
+    fn qualifies(&self, query: usize, logit: f32) -> bool {
+        let heap = &self.heaps[query];
+        logit > f32::NEG_INFINITY && (heap.len() < self.limit || heap.peek().is_some_and(|min| logit > min.0.logit))
+    }
+
+    /// Holds `candidate` back, returning the entry that no longer fits, if any.
+    fn offer(&mut self, query: usize, candidate: Candidate) -> Option<Candidate> {
+        let heap = &mut self.heaps[query];
+        heap.push(Reverse(candidate));
+        if heap.len() > self.limit {
+            heap.pop().map(|c| c.0)
+        } else {
+            None
+        }
+    }
+
+    /// Entries held back for any query, in ascending order.
+    fn rows(&self) -> Vec<usize> {
+        let mut rows: Vec<usize> = self.heaps.iter().flatten().map(|c| c.0.row).collect();
+        rows.sort_unstable();
+        rows.dedup();
+        rows
+    }
+
+    /// Held back entries along with the query they were held back for.
+    fn into_entries(self) -> impl Iterator<Item = (usize, Candidate)> {
+        self.heaps.into_iter().enumerate().flat_map(|(query, heap)| heap.into_iter().map(move |c| (query, c.0)))
+    }
+}
+
+/// Attention over approximate logits, whose highest logits are recomputed exactly before normalizing.
+/// For every query up to `limit` entries with the highest approximate logits are held back,
+/// all the other entries are accumulated right away.
+#[derive(Debug, Clone)]
+pub struct RescoringAttention {
+    attention: PartialAttention,
+    candidates: Candidates,
+}
+
+impl RescoringAttention {
+    /// Starts accumulating into `attention`, which has not seen any entry.
+    pub fn new(attention: PartialAttention, limit: usize) -> Self {
+        let queries = attention.max.len();
+        Self { attention, candidates: Candidates::new(queries, limit) }
+    }
+
+    /// Accumulates values `v` of entries starting at `first_row` weighted by approximate `logits`,
+    /// which have one row per query and one column per entry.
+    pub fn attend_logits(&mut self, mut logits: Array2<f32>, v: ArrayView2<f32>, first_row: usize) {
+        let batch = first_row..first_row + v.nrows();
+        for (query, mut row) in logits.rows_mut().into_iter().enumerate() {
+            for (entry, &logit) in row.iter().enumerate() {
+                if !self.candidates.qualifies(query, logit) {
+                    continue;
+                }
+                let candidate = Candidate { logit, row: first_row + entry, value: v.row(entry).to_owned() };
+                // Entries of this batch pushed out of the heap are accumulated with the rest of the batch below.
+                if let Some(evicted) = self.candidates.offer(query, candidate).filter(|c| !batch.contains(&c.row)) {
+                    self.attention.add(query, evicted.logit, evicted.value.view(), evicted.row);
+                }
+            }
+            for Reverse(candidate) in self.candidates.heaps[query].iter().filter(|c| batch.contains(&c.0.row)) {
+                row[candidate.row - first_row] = f32::NEG_INFINITY;
+            }
+        }
+        self.attention.attend_logits(logits, v, batch);
+    }
+
+    /// Entries held back for any query, in ascending order.
+    pub fn rows(&self) -> Vec<usize> {
+        self.candidates.rows()
+    }
+
+    /// Accumulates held back entries with logits given by `logit(query, row)`.
+    pub fn rescore(self, logit: impl Fn(usize, usize) -> f32) -> PartialAttention {
+        let mut attention = self.attention;
+        for (query, candidate) in self.candidates.into_entries() {
+            attention.add(query, logit(query, candidate.row), candidate.value.view(), candidate.row);
+        }
+        attention
+    }
+}
+
+impl Partial for RescoringAttention {
+    fn empty(&self) -> Self {
+        Self::new(self.attention.empty(), self.candidates.limit)
+    }
+
+    fn merge(&mut self, other: Self) {
+        self.attention.merge(other.attention);
+        for (query, candidate) in other.candidates.into_entries() {
+            if let Some(evicted) = self.candidates.offer(query, candidate) {
+                self.attention.add(query, evicted.logit, evicted.value.view(), evicted.row);
+            }
+        }
+    }
+}
+
+/// Sparse attention: only up to `k` entries with the highest logits of every query contribute.
+/// Entries are kept in a min-heap per query until the scan finishes, all the others are dropped.
+#[derive(Debug, Clone)]
+pub struct TopKAttention {
+    /// Attention the kept entries are accumulated into once the scan finishes.
+    attention: PartialAttention,
+    candidates: Candidates,
+}
+
+impl TopKAttention {
+    /// Keeps entries until they are accumulated into `attention`, which has not seen any entry.
+    pub fn new(attention: PartialAttention, k: usize) -> Self {
+        let queries = attention.max.len();
+        Self { attention, candidates: Candidates::new(queries, k) }
+    }
+
+    /// Offers entries `rows` with values `v` and `logits`, which have one row per query and one column per entry.
+    /// Entries with logit of negative infinity are skipped.
+    pub fn attend_logits(&mut self, logits: Array2<f32>, v: ArrayView2<f32>, rows: impl Iterator<Item = usize> + Clone) {
+        for (query, logits) in logits.rows().into_iter().enumerate() {
+            for (entry, (&logit, row)) in logits.iter().zip(rows.clone()).enumerate() {
+                if self.candidates.qualifies(query, logit) {
+                    self.candidates.offer(query, Candidate { logit, row, value: v.row(entry).to_owned() });
+                }
+            }
+        }
+    }
+
+    /// Softmax over the entries kept for every query.
+    pub fn finish(self) -> PartialAttention {
+        let mut attention = self.attention;
+        for (query, candidate) in self.candidates.into_entries() {
+            attention.add(query, candidate.logit, candidate.value.view(), candidate.row);
+        }
+        attention
+    }
+}
+
+impl Partial for TopKAttention {
+    fn empty(&self) -> Self {
+        Self::new(self.attention.empty(), self.candidates.limit)
+    }
+
+    fn merge(&mut self, other: Self) {
+        for (query, candidate) in other.candidates.into_entries() {
+            if self.candidates.qualifies(query, candidate.logit) {
+                self.candidates.offer(query, candidate);
+            }
+        }
+    }
+}
+
+/// Function attention logits are computed with.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Score {
+    /// Dot product.
+    #[default]
+    Dot,
+    /// Dot product divided by the square root of the vector size.
+    ScaledDot,
+    /// Cosine similarity. Zero for zero vectors.
+    Cosine,
+    /// Negative squared Euclidean distance, which makes the attention an RBF kernel smoother.
+    NegativeL2,
+}
+
+impl Score {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "dot" => Some(Score::Dot),
+            "scaled_dot" => Some(Score::ScaledDot),
+            "cosine" => Some(Score::Cosine),
+            "negative_l2" => Some(Score::NegativeL2),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Score::Dot => "dot",
+            Score::ScaledDot => "scaled_dot",
+            Score::Cosine => "cosine",
+            Score::NegativeL2 => "negative_l2",
+        }
+    }
+
+    /// Factor dot products are multiplied by to get scores, `None` if the score is not proportional to dot product.
+    fn dot_scale(&self, key_size: usize) -> Option<f32> {
+        match self {
+            Score::Dot => Some(1.),
+            Score::ScaledDot => Some(1. / (key_size as f32).sqrt()),
+            Score::Cosine | Score::NegativeL2 => None,
+        }
+    }
+
+    /// Scores of every query against every key, one row per query.
+    pub fn scores(&self, q: ArrayView2<f32>, k: ArrayView2<f32>) -> Array2<f32> {
+        let mut scores = q.dot(&k.t());
+        match self {
+            Score::Dot => {}
+            Score::ScaledDot => scores /= (q.ncols() as f32).sqrt(),
+            Score::Cosine => {
+                let q_norms = q.map_axis(Axis(1), |q| q.dot(&q).sqrt());
+                let k_norms = k.map_axis(Axis(1), |k| k.dot(&k).sqrt());
+                Zip::from(scores.rows_mut()).and(&q_norms).for_each(|mut row, &q_norm| {
+                    Zip::from(&mut row).and(&k_norms).for_each(|score, &k_norm| {
+                        let norm = q_norm * k_norm;
+                        *score = if norm > 0. { *score / norm } else { 0. };
+                    });
+                });
+            }
+            Score::NegativeL2 => {
+                let q_norms = q.map_axis(Axis(1), |q| q.dot(&q));
+                let k_norms = k.map_axis(Axis(1), |k| k.dot(&k));
+                Zip::from(scores.rows_mut()).and(&q_norms).for_each(|mut row, &q_norm| {
+                    Zip::from(&mut row).and(&k_norms).for_each(|score, &k_norm| *score = (2. * *score - q_norm - k_norm).min(0.));
+                });
+            }
+        }
+        scores
+    }
+}
+
+/// Score logits are computed with along with the temperature they are divided by.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Scoring {
+    pub score: Score,
+    pub temperature: f32,
+    /// Number of parts queries and keys are split into, each scored separately.
+    pub heads: usize,
+}
+
+impl Scoring {
+    /// Logits of every query against every key, one row per query and head, heads of a query being adjacent.
+    pub fn logits(&self, q: ArrayView2<f32>, k: ArrayView2<f32>) -> Array2<f32> {
+        let mut logits = if self.heads == 1 {
+            self.score.scores(q, k)
+        } else {
+            let width = q.ncols() / self.heads;
+            let mut logits = Array2::zeros((q.nrows() * self.heads, k.nrows()));
+            for head in 0..self.heads {
+                let part = s![.., head * width..(head + 1) * width];
+                logits.slice_mut(s![head..;self.heads as isize, ..]).assign(&self.score.scores(q.slice(part), k.slice(part)));
+            }
+            logits
+        };
+        if self.temperature != 1. {
+            logits /= self.temperature;
+        }
+        logits
+    }
+
+    /// Whether entries with the highest dot products are also the ones with the highest logits.
+    pub fn ranks_by_dot(&self) -> bool {
+        self.dot_scale(1).is_some()
+    }
+
+    /// Factor dot products of queries with keys of `key_size` elements are multiplied by to get logits.
+    /// Returns `None` if logits can not be derived from dot products alone.
+    pub fn dot_scale(&self, key_size: usize) -> Option<f32> {
+        Some(self.score.dot_scale(key_size)? / self.temperature)
+    }
+}
+
+/// How positions of entries in the bucket affect their logits.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "kind")]
+pub enum Position {
+    /// Logits depend on keys only.
+    #[default]
+    None,
+    /// ALiBi: logits decrease by `slope` for every entry inserted after the entry.
+    Alibi { slope: f32 },
+    /// Rotary position embeddings: pairs of key elements of every head are rotated by angles proportional to
+    /// the distance of the entry from the queries, with frequencies decreasing geometrically from `base`.
+    Rope { base: f32 },
+    /// Exponential time decay: attention weights halve every `half_life` seconds since the entry was inserted.
+    Decay { half_life: f32 },
+}
+
+impl Position {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Position::None => "none",
+            Position::Alibi { .. } => "alibi",
+            Position::Rope { .. } => "rope",
+            Position::Decay { .. } => "decay",
+        }
+    }
+}
+
+/// Positions of the entries a scan attends over, queries being positioned right after the last entry.
+#[derive(Debug, Clone, Default)]
+pub struct Positions {
+    pub position: Position,
+    /// Number of entries in the bucket, which is the position of the queries.
+    pub entries: usize,
+    /// Insertion time of every entry in milliseconds since the Unix epoch, only kept for time decay.
+    pub timestamps: Vec<u64>,
+    /// Time of the scan in milliseconds since the Unix epoch.
+    pub now: u64,
+}
+
+impl Positions {
+    /// Rotates keys `k` of entries `rows` by their distance from the queries if rotary embeddings are used,
+    /// each of `heads` heads separately. Queries are left as they are, as only the relative rotation matters.
+    pub fn rotate(&self, k: ArrayView2<f32>, rows: impl Iterator<Item = usize>, heads: usize) -> Option<Array2<f32>> {
+        let Position::Rope { base } = self.position else {
+            return None;
+        };
+        let width = k.ncols() / heads;
+        let frequencies: Vec<f64> = (0..width / 2).map(|i| (base as f64).powf(-2. * i as f64 / width as f64)).collect();
+        let mut k = k.to_owned();
+        for (mut key, row) in k.rows_mut().into_iter().zip(rows) {
+            let distance = row as f64 - self.entries as f64;
+            for mut head in key.exact_chunks_mut(width) {
+                for (i, frequency) in frequencies.iter().enumerate() {
+                    let (sin, cos) = (distance * frequency).sin_cos();
+                    let (x, y) = (head[2 * i], head[2 * i + 1]);
+                    head[2 * i] = x * cos as f32 - y * sin as f32;
+                    head[2 * i + 1] = x * sin as f32 + y * cos as f32;
+                }
+            }
+        }
+        Some(k)
+    }
+
+    /// Adds position bias of entries `rows` to `logits`, which have one column per entry.
+    pub fn bias(&self, logits: &mut Array2<f32>, rows: impl Iterator<Item = usize>) {
+        let bias = |row: usize| match self.position {
+            Position::None | Position::Rope { .. } => None,
+            Position::Alibi { slope } => Some(-slope * (self.entries - row) as f32),
+            Position::Decay { half_life } => {
+                let age = self.now.saturating_sub(self.timestamps[row]) as f32 / 1000.;
+                Some(-std::f32::consts::LN_2 * age / half_life)
+            }
+        };
+        for (mut column, row) in logits.columns_mut().into_iter().zip(rows) {
+            match bias(row) {
+                None => return,
+                Some(bias) => column += bias,
+            }
+        }
+    }
+}
+
+/// How similar a key is to a query when searching for nearest neighbours.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub enum Metric {
+    /// Dot product, higher is better.
+    #[default]
+    Dot,
+    /// Cosine similarity, higher is better. Zero for zero vectors.
+    Cosine,
+    /// Euclidean distance, lower is better.
+    L2,
+}
+
+impl Metric {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "dot" => Some(Metric::Dot),
+            "cosine" => Some(Metric::Cosine),
+            "l2" => Some(Metric::L2),
+            _ => None,
+        }
+    }
+
+    /// Scores of every query against every key, higher is better. L2 scores are negative squared distances.
+    pub fn scores(&self, q: &Array2<f32>, k: ArrayView2<f32>) -> Array2<f32> {
+        let score = match self {
+            Metric::Dot => Score::Dot,
+            Metric::Cosine => Score::Cosine,
+            Metric::L2 => Score::NegativeL2,
+        };
+        score.scores(q.view(), k)
+    }
+
+    /// Converts a score computed by [`Metric::scores`] to the value reported to the client.
+    pub fn report(&self, score: f32) -> f32 {
+        match self {
+            Metric::Dot | Metric::Cosine => score,
+            Metric::L2 => (-score).sqrt(),
+        }
+    }
+}
+
+/// Entry found by a nearest neighbour search.
+#[derive(Debug, Clone)]
+pub struct Neighbour {
+    pub row: usize,
+    pub score: f32,
+    /// Value of the entry, empty unless values were requested.
+    pub value: Array1<f32>,
+}
+
+/// Up to `k` entries with the highest scores for every query, optionally along with their values.
+#[derive(Debug, Clone)]
+pub struct NearestNeighbours {
+    candidates: Candidates,
+}
+
+impl NearestNeighbours {
+    pub fn new(queries: usize, k: usize) -> Self {
+        Self { candidates: Candidates::new(queries, k) }
+    }
+
+    /// Offers entries `rows` with `scores`, which have one row per query and one column per entry.
+    /// Values `v` are kept along with the entries if given.
+    pub fn offer(&mut self, scores: Array2<f32>, v: Option<ArrayView2<f32>>, rows: impl Iterator<Item = usize> + Clone) {
+        for (query, scores) in scores.rows().into_iter().enumerate() {
+            for (entry, (&score, row)) in scores.iter().zip(rows.clone()).enumerate() {
+                if self.candidates.qualifies(query, score) {
+                    let value = v.map_or_else(|| Array1::zeros(0), |v| v.row(entry).to_owned());
+                    self.candidates.offer(query, Candidate { logit: score, row, value });
+                }
+            }
+        }
+    }
+
+    /// Entries found for every query, best first.
+    pub fn finish(self) -> Vec<Vec<Neighbour>> {
+        self.candidates.heaps.into_iter().map(|heap| {
+            heap.into_sorted_vec().into_iter().map(|c| Neighbour { row: c.0.row, score: c.0.logit, value: c.0.value }).collect()
+        }).collect()
+    }
+}
+
+impl Partial for NearestNeighbours {
+    fn empty(&self) -> Self {
+        Self { candidates: self.candidates.empty() }
+    }
+
+    fn merge(&mut self, other: Self) {
+        for (query, candidate) in other.candidates.into_entries() {
+            if self.candidates.qualifies(query, candidate.logit) {
+                self.candidates.offer(query, candidate);
+            }
+        }
+    }
+}
+
+/// Shape of a single scan, used to estimate how much memory it needs.
+#[derive(Debug, Copy, Clone)]
+pub struct ScanShape {
+    pub queries: usize,
+    /// Number of elements of every query and key.
+    pub key_size: usize,
+    /// Number of elements of every value.
+    pub value_size: usize,
+    /// Number of heads every query attends with.
+    pub heads: usize,
+    pub threads: usize,
+    /// Size in bytes of a single stored key the scan reads.
+    pub key_row_size: usize,
+    /// Whether keys of every batch are converted to f32 before computing logits.
+    pub widened_keys: bool,
+    /// Size in bytes of a single stored value.
+    pub value_row_size: usize,
+    /// Number of entries held back for rescoring per query.
+    pub candidates: usize,
+    /// Bytes of lookup tables computed once per scan.
+    pub lookup_tables: usize,
+    /// Number of batches of raw entries held in memory at once. Zero for memory-mapped scans.
+    pub buffered_batches: usize,
+}
+
+impl ScanShape {
+    /// Estimates bytes used by the scan when every worker attends `batch_rows` entries at once.
+    pub fn memory(&self, batch_rows: usize) -> usize {
+        let f = size_of::<f32>();
+        let key_row = self.key_size * f;
+        let value_row = self.value_size * f;
+        // Per worker: logits of every query against the batch plus its partial result.
+        let logits = self.queries * self.heads * batch_rows * f;
+        let partial = self.queries * (self.value_size + 2 * self.heads) * f;
+        // Per worker: keys and values of the batch converted to f32, if they are stored as something else.
+        let widened = (self.widened_keys as usize * key_row + (self.value_row_size != value_row) as usize * value_row) * batch_rows;
+        // Per worker: copies of held back values along with their logits and rows.
+        let candidates = self.queries * self.heads * self.candidates * (value_row + 2 * size_of::<usize>());
+        // Exact keys of held back entries read at the end of the scan.
+        let rescored = self.queries * self.heads * self.candidates * key_row;
+        // Keys and values of a chunk, which is `threads` batches.
+        let entries = self.threads * batch_rows * (self.key_row_size + self.value_row_size);
+        self.threads * (logits + partial + widened + candidates) + rescored + self.lookup_tables + self.buffered_batches * entries
+    }
+
+    /// Largest number of entries per worker batch that keeps the scan within `budget` bytes, at least one.
+    pub fn batch_rows_for_budget(&self, budget: usize) -> usize {
+        let fixed = self.memory(0);
+        let per_row = self.memory(1) - fixed;
+        (budget.saturating_sub(fixed) / per_row).max(1)
+    }
+}
+
+/// Runs attention over bucket batches on a dedicated pool of worker threads.
+pub struct ScanExecutor {
+    pool: ThreadPool,
+}
+
+impl ScanExecutor {
+    pub fn new(threads: usize) -> Result<Self, ThreadPoolBuildError> {
+        Ok(Self {
+            pool: ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .thread_name(|i| format!("qkv-scan-{i}"))
+                .build()?,
+        })
+    }
+
+    pub fn threads(&self) -> usize {
+        self.pool.current_num_threads()
+    }
+
+    /// Runs `f` on the worker pool, so that any parallel work inside it uses the scan workers.
+    pub fn install<R: Send>(&self, f: impl FnOnce() -> R + Send) -> R {
+        self.pool.install(f)
+    }
+
+    /// Number of entries that should be handed to [`ScanExecutor::reduce`] at once to keep every worker busy.
+    pub fn chunk_size(&self, batch_rows: usize) -> usize {
+        batch_rows * self.threads()
+    }
+
+    /// Splits entries starting at `first_row` into contiguous ranges, calls `f` on them in parallel in batches
+    /// of `batch_rows` along with the index of the first entry of the batch, and merges the partial results into `state`.
+    /// Any conversion of the stored elements done by `f` happens on the worker that processes the batch.
+    pub fn reduce<K, V, P, F>(&self, state: &mut P, first_row: usize, k: ArrayView2<K>, v: ArrayView2<V>, batch_rows: usize, f: &F)
+    where
+        K: Sync,
+        V: Sync,
+        P: Partial,
+        F: Fn(&mut P, usize, ArrayView2<K>, ArrayView2<V>) + Sync,
+    {
+        let rows = k.nrows();
+        if rows == 0 {
+            return;
+        }
+        let range_size = rows.div_ceil(self.threads()).max(1);
+        let empty = state.empty();
+        let partial = self.pool.install(|| {
+            (0..rows)
+                .into_par_iter()
+                .step_by(range_size)
+                .map(|start| {
+                    let end = (start + range_size).min(rows);
+                    let mut partial = empty.clone();
+                    for batch_start in (start..end).step_by(batch_rows.max(1)) {
+                        let batch_end = (batch_start + batch_rows).min(end);
+                        f(&mut partial, first_row + batch_start, k.slice(s![batch_start..batch_end, ..]), v.slice(s![batch_start..batch_end, ..]));
+                    }
+                    partial
+                })
+                .reduce(|| empty.clone(), |mut a, b| {
+                    a.merge(b);
+                    a
+                })
+        });
+        state.merge(partial);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+
+    /// Softmax attention computed over all entries at once, masked entries having logit of negative infinity.
+    /// Queries that see no entry get a zero vector.
+    fn full_attention(logits: &Array2<f32>, v: &Array2<f32>) -> Array2<f32> {
+        let mut out = Array2::zeros((logits.nrows(), v.ncols()));
+        for (logits, mut out) in logits.rows().into_iter().zip(out.rows_mut()) {
+            let max = logits.fold(f32::NEG_INFINITY, |a, &b| a.max(b));
+            if max == f32::NEG_INFINITY {
+                continue;
+            }
+            let weights = logits.mapv(|x| (x - max).exp());
+            out.assign(&(weights.dot(v) / weights.sum()));
+        }
+        out
+    }
+
+    /// Attends over the given column ranges of `logits` separately and merges the partials in reverse order.
+    fn chunked_attention(logits: &Array2<f32>, v: &Array2<f32>, chunks: &[(usize, usize)]) -> PartialAttention {
+        let empty = PartialAttention::new(logits.nrows(), 1, v.ncols());
+        let mut partials: Vec<PartialAttention> = chunks.iter().map(|&(start, end)| {
+            let mut partial = empty.empty();
+            partial.attend_logits(logits.slice(s![.., start..end]).to_owned(), v.slice(s![start..end, ..]), start..end);
+            partial
+        }).collect();
+        let mut merged = partials.pop().expect("At least one chunk is given");
+        while let Some(partial) = partials.pop() {
+            merged.merge(partial);
+        }
+        merged
+    }
+
+    fn assert_close(a: &Array2<f32>, b: &Array2<f32>) {
+        assert_eq!(a.dim(), b.dim());
+        for (x, y) in a.iter().zip(b) {
+            assert!((x - y).abs() < 1e-5, "{a} differs from {b}");
+        }
+    }
+
+    #[test]
+    fn merged_chunks_match_full_softmax() {
+        let logits = array![
+            [0.5, -1., 2., 30., -20., 1.5],
+            [-3., 0., 0., 1., 2., -0.5],
+        ];
+        let v = array![[1., 0., 2.], [0., 1., -1.], [3., 3., 0.], [-1., 2., 1.], [0.5, 0.5, 0.5], [2., -2., 4.]];
+        let expected = full_attention(&logits, &v);
+        for chunks in [vec![(0, 6)], vec![(0, 2), (2, 5), (5, 6)], vec![(0, 1), (1, 2), (2, 3), (3, 4), (4, 5), (5, 6)]] {
+            assert_close(&chunked_attention(&logits, &v, &chunks).finish(), &expected);
+        }
+    }
+
+    #[test]
+    fn fully_masked_chunks_do_not_change_the_result() {
+        let inf = f32::NEG_INFINITY;
+        let logits = array![
+            [inf, inf, 1., 2., inf, inf],
+            [inf, inf, inf, inf, inf, inf],
+            [inf, inf, 0.5, inf, -1., 3.],
+        ];
+        let v = array![[1., 0.], [0., 1.], [3., 3.], [-1., 2.], [0.5, 0.5], [2., -2.]];
+        let expected = full_attention(&logits, &v);
+        assert_eq!(expected.row(1), array![0., 0.]);
+        // The first chunk is masked for every query, the last one for the first two.
+        for chunks in [vec![(0, 2), (2, 4), (4, 6)], vec![(0, 1), (1, 2), (2, 3), (3, 4), (4, 5), (5, 6)]] {
+            let merged = chunked_attention(&logits, &v, &chunks);
+            assert_eq!(merged.entropy()[1], 0.);
+            let out = merged.finish();
+            assert!(out.iter().all(|x| x.is_finite()));
+            assert_close(&out, &expected);
+        }
+    }
+
+    #[test]
+    fn merged_heads_match_a_single_pass() {
+        let logits = array![
+            [1., 0., -2., 4.],
+            [0., 0., 3., f32::NEG_INFINITY],
+            [-1., 2., 0.5, 0.],
+            [f32::NEG_INFINITY, f32::NEG_INFINITY, 1., 1.],
+        ];
+        let v = array![[1., 0., 2., -1.], [0., 1., 1., 1.], [3., 3., 0., 2.], [-1., 2., 1., 0.]];
+        let mut single = PartialAttention::new(2, 2, 4);
+        single.attend_logits(logits.clone(), v.view(), 0..4);
+        let mut merged = single.empty();
+        let mut second = single.empty();
+        merged.attend_logits(logits.slice(s![.., ..2]).to_owned(), v.slice(s![..2, ..]), 0..2);
+        second.attend_logits(logits.slice(s![.., 2..]).to_owned(), v.slice(s![2.., ..]), 2..4);
+        merged.merge(second);
+        assert_close(&merged.entropy().insert_axis(Axis(0)), &single.entropy().insert_axis(Axis(0)));
+        assert_close(&merged.finish(), &single.finish());
+    }
+}
@@ -0,0 +1,46 @@
+use ndarray::{Array2, ArrayView2, Axis};
+
+/// Index of the nearest centroid for every row of `data`.
+pub fn nearest_centroids(data: ArrayView2<f32>, centroids: ArrayView2<f32>) -> Vec<usize> {
+    // Nearest centroid minimizes `|c|^2 - 2 x.c`, the rest of the squared distance is the same for all of them.
+    let norms = centroids.map_axis(Axis(1), |c| c.dot(&c));
+    let dots = data.dot(&centroids.t());
+    dots.rows()
+        .into_iter()
+        .map(|row| {
+            row.iter()
+                .zip(norms.iter())
+                .map(|(dot, norm)| norm - 2. * dot)
+                .enumerate()
+                .min_by(|a, b| a.1.total_cmp(&b.1))
+                .map_or(0, |(c, _)| c)
+        })
+        .collect()
+}
+
+/// Clusters rows of `data` into `k` centroids. Initial centroids are spread evenly over the data.
+pub fn kmeans(data: ArrayView2<f32>, k: usize, iterations: usize) -> Array2<f32> {
+    let n = data.nrows();
+    let mut centroids = Array2::from_shape_fn((k, data.ncols()), |(c, j)| data[[c * n / k, j]]);
+    let mut assignment = vec![usize::MAX; n];
+    for _ in 0..iterations {
+        let nearest = nearest_centroids(data, centroids.view());
+        if nearest == assignment {
+            break;
+        }
+        assignment = nearest;
+        let mut sums = Array2::<f32>::zeros(centroids.dim());
+        let mut counts = vec![0usize; k];
+        for (row, &c) in data.rows().into_iter().zip(assignment.iter()) {
+            sums.row_mut(c).scaled_add(1., &row);
+            counts[c] += 1;
+        }
+        // Centroids that lost all their rows stay where they were.
+        for (c, &count) in counts.iter().enumerate() {
+            if count > 0 {
+                centroids.row_mut(c).assign(&(&sums.row(c) / count as f32));
+            }
+        }
+    }
+    centroids
+}
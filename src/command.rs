@@ -1,12 +1,13 @@
-use anyhow::Context;
-use std::iter::{zip, Peekable, Zip};
+use std::collections::BTreeMap;
+use std::iter::{zip, Peekable};
 use thiserror::Error;
+use crate::metadata::{Comparison, Filter, Metadata, Value};
 
-static KEYWORDS: &'static [&'static str] = &[
+static KEYWORDS: &[&str] = &[
     // Operations
-    "CREATE", "INSERT", "SCAN", // Entities
+    "CREATE", "INSERT", "SCAN", "SEARCH", "DESCRIBE", "COMPRESS", "BUILD", "SET", "DELETE", "COMPACT", "UPDATE", "UPSERT", "GET", // Entities
     "DATABASE", "BUCKET", "QUERIES", "KEYS", "VALUES", // Helpers
-    "IF", "NOT", "EXISTS", "WITH", "INTO", "INSIDE", "AND",
+    "IF", "NOT", "EXISTS", "WITH", "INTO", "INSIDE", "AND", "RETURN", "WHERE", "OR", "IN",
 ];
 
 #[derive(Debug, Error)]
@@ -21,12 +22,16 @@ pub enum ParseError {
     UnexpectedEOS,
     #[error("You must specify bucket to insert data to")]
     NoBucketInInsert,
-}
-
-#[derive(Debug, PartialEq)]
-pub enum IfExists {
-    Fail,
-    Skip,
+    #[error("You must specify bucket")]
+    NoBucket,
+    #[error("Invalid number `{0}`")]
+    InvalidNumber(String),
+    #[error("{list} must be given for each of {entries} entries, got {got}")]
+    CountMismatch { list: &'static str, entries: usize, got: usize },
+    #[error("Expected a single vector, got {0}")]
+    SingleVectorExpected(usize),
+    #[error("Range {start}..{end} ends before it starts")]
+    InvalidRange { start: usize, end: usize },
 }
 
 #[derive(Debug, PartialEq)]
@@ -36,6 +41,15 @@ pub enum ScanTargetBucket {
     Physical(String),
 }
 
+/// Entries read by `GET`, given by their numbers in the bucket.
+#[derive(Debug, PartialEq)]
+pub enum EntrySelection {
+    /// Entries listed by `IDS (a, b, ...)`, in the given order.
+    Ids(Vec<usize>),
+    /// Entries from `start` up to, but not including, `end` given by `RANGE start..end`.
+    Range { start: usize, end: usize },
+}
+
 #[derive(Debug, PartialEq)]
 pub enum Command {
     CreateDatabase {
@@ -51,14 +65,75 @@ pub enum Command {
         database: String,
         bucket: String,
         entries: Vec<(Vec<f32>, Vec<f32>)>,
+        /// Metadata of every entry if `METADATA` was given.
+        metadata: Option<Vec<Metadata>>,
+        /// External id of every entry if the command is `UPSERT`, entries replace those written with the same ids.
+        ids: Option<Vec<String>>,
         properties: PropertyList,
     },
     Scan {
         database: String,
         bucket: ScanTargetBucket,
         queries: Vec<Vec<f32>>,
+        /// Condition on metadata of the entries attended to, given by `WHERE`.
+        filter: Option<Filter>,
+        properties: PropertyList,
+        /// Whether `RETURN WEIGHTS` was given.
+        return_weights: bool,
+    },
+    Search {
+        database: String,
+        bucket: String,
+        queries: Vec<Vec<f32>>,
+        properties: PropertyList,
+        /// Whether `RETURN VALUES` was given.
+        return_values: bool,
+    },
+    Describe {
+        database: String,
+        bucket: Option<String>,
+    },
+    Compress {
+        database: String,
+        bucket: String,
+        properties: PropertyList,
+    },
+    BuildIndex {
+        database: String,
+        bucket: String,
         properties: PropertyList,
     },
+    /// Overwrites values of entries of the bucket matching `filter` with `value`.
+    Update {
+        database: String,
+        bucket: String,
+        value: Vec<f32>,
+        filter: Filter,
+    },
+    /// Deletes entries of the bucket matching `filter`.
+    Delete {
+        database: String,
+        bucket: String,
+        filter: Filter,
+    },
+    /// Removes deleted entries from files of the bucket.
+    Compact {
+        database: String,
+        bucket: String,
+    },
+    /// Reads keys and values of the selected entries without scanning the bucket.
+    Get {
+        database: String,
+        bucket: String,
+        entries: EntrySelection,
+    },
+    /// Replaces projection matrices of the database, each given as a list of rows. Omitted matrices are not applied.
+    SetProjection {
+        database: String,
+        wq: Option<Vec<Vec<f32>>>,
+        wk: Option<Vec<Vec<f32>>>,
+        wv: Option<Vec<Vec<f32>>>,
+    },
     Dummy,
 }
 
@@ -97,6 +172,8 @@ enum Token {
     Identifier(String),
     Punctuation(String),
     Number(String),
+    /// Content of a literal in single quotes, two quotes in a row standing for one.
+    String(String),
 }
 
 impl Token {
@@ -106,6 +183,7 @@ impl Token {
             Token::Identifier(_) => "identifier",
             Token::Punctuation(_) => "punctuation",
             Token::Number(_) => "number",
+            Token::String(_) => "string",
         }
     }
 
@@ -115,8 +193,13 @@ impl Token {
             Token::Identifier(c) => c.as_str(),
             Token::Punctuation(c) => c.as_str(),
             Token::Number(c) => c.as_str(),
+            Token::String(c) => c.as_str(),
         }
     }
+
+    pub fn is_punctuation(&self, c: &str) -> bool {
+        matches!(self, Token::Punctuation(content) if content == c)
+    }
 }
 
 impl Command {
@@ -145,6 +228,7 @@ impl Command {
             if tok.ty() == "punctuation" && tok.content() == ")" {
                 break;
             }
+            let mut tok = tok;
             if !data.is_empty() {
                 if tok.ty() != "punctuation" || tok.content() != "," {
                     return Err(ParseError::UnexpectedToken {
@@ -157,6 +241,7 @@ impl Command {
                 if tok_.is_none() {
                     return Err(ParseError::UnexpectedEOS);
                 }
+                tok = tok_.unwrap();
             }
 
             if tok.ty() != "punctuation" || tok.content() != "[" {
@@ -206,13 +291,36 @@ impl Command {
                         token: tok.content().to_string(),
                     });
                 }
-                numbers.push(tok.content().parse().expect("Failed to parse float."))
+                numbers.push(tok.content().parse().map_err(|_| ParseError::InvalidNumber(tok.content().to_string()))?)
             }
             data.push(numbers);
         }
         Ok(AstVecData(data))
     }
 
+    /// Parses value of a property: a number, a bare word such as `f16` or a string literal.
+    fn parse_property_value(token: Option<Token>) -> Result<PropertyValue, ParseError> {
+        let value = match token {
+            None => return Err(ParseError::UnexpectedEOS),
+            Some(value) => value,
+        };
+        match value {
+            Token::Number(value) => {
+                if let Ok(value) = value.parse() {
+                    Ok(PropertyValue::Integer(value))
+                } else {
+                    Ok(PropertyValue::Float(value.parse().map_err(|_| ParseError::InvalidNumber(value.clone()))?))
+                }
+            }
+            Token::Identifier(value) | Token::String(value) => Ok(PropertyValue::String(value)),
+            value => Err(ParseError::UnexpectedToken {
+                line: 0,
+                col: 0,
+                token: value.content().to_string(),
+            }),
+        }
+    }
+
     fn parse_with_clause(
         content: &mut Peekable<impl Iterator<Item = Token>>,
     ) -> Result<AstWithClauseData, ParseError> {
@@ -247,29 +355,8 @@ impl Command {
                 });
             }
 
-            let value = content.next();
-            if value.is_none() {
-                return Err(ParseError::UnexpectedEOS);
-            }
-            let value = value.unwrap();
-            if value.ty() != "number" {
-                return Err(ParseError::UnexpectedToken {
-                    line: 0,
-                    col: 0,
-                    token: value.content().to_string(),
-                });
-            }
-
-            let value = value.content().to_string();
-
-            let mut props = vec![Property {
-                name,
-                data: if value.contains('.') {
-                    PropertyValue::Float(value.parse().expect("Failed to parse float value."))
-                } else {
-                    PropertyValue::Integer(value.parse().expect("Failed to parse integer."))
-                },
-            }];
+            let data = Command::parse_property_value(content.next())?;
+            let mut props = vec![Property { name, data }];
             while let Some(_and) = content
                 .next_if(|tok| tok.ty() == "keyword" && tok.content().to_uppercase() == "AND")
             {
@@ -301,28 +388,8 @@ impl Command {
                     });
                 }
 
-                let value = content.next();
-                if value.is_none() {
-                    return Err(ParseError::UnexpectedEOS);
-                }
-                let value = value.unwrap();
-                if value.ty() != "number" {
-                    return Err(ParseError::UnexpectedToken {
-                        line: 0,
-                        col: 0,
-                        token: value.content().to_string(),
-                    });
-                }
-
-                let value = value.content().to_string();
-                props.push(Property {
-                    name,
-                    data: if value.contains('.') {
-                        PropertyValue::Float(value.parse().expect("Failed to parse float value."))
-                    } else {
-                        PropertyValue::Integer(value.parse().expect("Failed to parse integer."))
-                    },
-                })
+                let data = Command::parse_property_value(content.next())?;
+                props.push(Property { name, data })
             }
             Ok(AstWithClauseData(props))
         } else {
@@ -330,6 +397,166 @@ impl Command {
         }
     }
 
+    /// Parses metadata of inserted entries: `({name = value, ...}, ...)`, values being numbers or string literals.
+    fn parse_metadata(content: &mut Peekable<impl Iterator<Item = Token>>) -> Result<Vec<Metadata>, ParseError> {
+        if content.next_if(|tok| tok.is_punctuation("(")).is_none() {
+            return Err(Command::unexpected_token(content.next()));
+        }
+        let mut entries = vec![];
+        while content.next_if(|tok| tok.is_punctuation(")")).is_none() {
+            if !entries.is_empty() && content.next_if(|tok| tok.is_punctuation(",")).is_none() {
+                return Err(Command::unexpected_token(content.next()));
+            }
+            if content.next_if(|tok| tok.is_punctuation("{")).is_none() {
+                return Err(Command::unexpected_token(content.next()));
+            }
+            let mut fields = BTreeMap::new();
+            while content.next_if(|tok| tok.is_punctuation("}")).is_none() {
+                if !fields.is_empty() && content.next_if(|tok| tok.is_punctuation(",")).is_none() {
+                    return Err(Command::unexpected_token(content.next()));
+                }
+                let name = match content.next() {
+                    Some(Token::Identifier(name)) => name,
+                    token => return Err(Command::unexpected_token(token)),
+                };
+                if content.next_if(|tok| tok.is_punctuation("=")).is_none() {
+                    return Err(Command::unexpected_token(content.next()));
+                }
+                let value = Command::parse_literal(content.next())?;
+                if fields.insert(name.clone(), value).is_some() {
+                    return Err(ParseError::UnexpectedToken { line: 0, col: 0, token: name });
+                }
+            }
+            entries.push(Metadata(fields));
+        }
+        Ok(entries)
+    }
+
+    /// Parses external ids of upserted entries: `(id, ...)`, each a string literal or an integer taken as its decimal form.
+    fn parse_ids(content: &mut Peekable<impl Iterator<Item = Token>>) -> Result<Vec<String>, ParseError> {
+        if content.next_if(|tok| tok.is_punctuation("(")).is_none() {
+            return Err(Command::unexpected_token(content.next()));
+        }
+        let mut ids = vec![];
+        loop {
+            match Command::parse_literal(content.next())? {
+                Value::String(id) => ids.push(id),
+                Value::Integer(id) => ids.push(id.to_string()),
+                Value::Float(id) => return Err(ParseError::UnexpectedToken { line: 0, col: 0, token: id.to_string() }),
+            }
+            if content.next_if(|tok| tok.is_punctuation(",")).is_none() {
+                break;
+            }
+        }
+        if content.next_if(|tok| tok.is_punctuation(")")).is_none() {
+            return Err(Command::unexpected_token(content.next()));
+        }
+        Ok(ids)
+    }
+
+    /// Parses number of an entry in its bucket.
+    fn parse_entry_id(token: Option<Token>) -> Result<usize, ParseError> {
+        match token {
+            None => Err(ParseError::UnexpectedEOS),
+            Some(Token::Number(number)) => number.parse().map_err(|_| ParseError::UnexpectedToken { line: 0, col: 0, token: number }),
+            Some(token) => Err(ParseError::UnexpectedToken { line: 0, col: 0, token: token.content().to_string() }),
+        }
+    }
+
+    /// Parses numbers of entries read by `GET`: `(id, ...)`.
+    fn parse_entry_ids(content: &mut Peekable<impl Iterator<Item = Token>>) -> Result<Vec<usize>, ParseError> {
+        if content.next_if(|tok| tok.is_punctuation("(")).is_none() {
+            return Err(Command::unexpected_token(content.next()));
+        }
+        let mut ids = vec![Command::parse_entry_id(content.next())?];
+        while content.next_if(|tok| tok.is_punctuation(",")).is_some() {
+            ids.push(Command::parse_entry_id(content.next())?);
+        }
+        if content.next_if(|tok| tok.is_punctuation(")")).is_none() {
+            return Err(Command::unexpected_token(content.next()));
+        }
+        Ok(ids)
+    }
+
+    /// Parses a number or a string literal.
+    fn parse_literal(token: Option<Token>) -> Result<Value, ParseError> {
+        match token {
+            None => Err(ParseError::UnexpectedEOS),
+            Some(Token::Number(value)) => match value.parse() {
+                Ok(value) => Ok(Value::Integer(value)),
+                Err(_) => Ok(Value::Float(value.parse().map_err(|_| ParseError::InvalidNumber(value.clone()))?)),
+            },
+            Some(Token::String(value)) => Ok(Value::String(value)),
+            Some(token) => Err(ParseError::UnexpectedToken { line: 0, col: 0, token: token.content().to_string() }),
+        }
+    }
+
+    /// Parses condition of `WHERE` clause, if one is given.
+    fn parse_where_clause(content: &mut Peekable<impl Iterator<Item = Token>>) -> Result<Option<Filter>, ParseError> {
+        match content.next_if(|tok| tok.ty() == "keyword" && tok.content() == "WHERE") {
+            Some(_) => Ok(Some(Command::parse_filter(content)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Parses conditions joined by `OR`, which binds weaker than `AND`.
+    fn parse_filter(content: &mut Peekable<impl Iterator<Item = Token>>) -> Result<Filter, ParseError> {
+        let mut filter = Command::parse_conjunction(content)?;
+        while content.next_if(|tok| tok.ty() == "keyword" && tok.content() == "OR").is_some() {
+            filter = Filter::Or(Box::new(filter), Box::new(Command::parse_conjunction(content)?));
+        }
+        Ok(filter)
+    }
+
+    /// Parses conditions joined by `AND`.
+    fn parse_conjunction(content: &mut Peekable<impl Iterator<Item = Token>>) -> Result<Filter, ParseError> {
+        let mut filter = Command::parse_condition(content)?;
+        while content.next_if(|tok| tok.ty() == "keyword" && tok.content() == "AND").is_some() {
+            filter = Filter::And(Box::new(filter), Box::new(Command::parse_condition(content)?));
+        }
+        Ok(filter)
+    }
+
+    /// Parses `NOT condition`, `(filter)`, `field IN (values)`, `field NOT IN (values)` or comparison `field <op> value`.
+    fn parse_condition(content: &mut Peekable<impl Iterator<Item = Token>>) -> Result<Filter, ParseError> {
+        if content.next_if(|tok| tok.ty() == "keyword" && tok.content() == "NOT").is_some() {
+            return Ok(Filter::Not(Box::new(Command::parse_condition(content)?)));
+        }
+        if content.next_if(|tok| tok.is_punctuation("(")).is_some() {
+            let filter = Command::parse_filter(content)?;
+            if content.next_if(|tok| tok.is_punctuation(")")).is_none() {
+                return Err(Command::unexpected_token(content.next()));
+            }
+            return Ok(filter);
+        }
+        let field = match content.next() {
+            Some(Token::Identifier(field)) => field,
+            token => return Err(Command::unexpected_token(token)),
+        };
+        let negated = content.next_if(|tok| tok.ty() == "keyword" && tok.content() == "NOT").is_some();
+        if negated || content.peek().is_some_and(|tok| tok.ty() == "keyword" && tok.content() == "IN") {
+            Command::force_keyword(Some("IN"), content.next())?;
+            if content.next_if(|tok| tok.is_punctuation("(")).is_none() {
+                return Err(Command::unexpected_token(content.next()));
+            }
+            let mut values = vec![Command::parse_literal(content.next())?];
+            while content.next_if(|tok| tok.is_punctuation(",")).is_some() {
+                values.push(Command::parse_literal(content.next())?);
+            }
+            if content.next_if(|tok| tok.is_punctuation(")")).is_none() {
+                return Err(Command::unexpected_token(content.next()));
+            }
+            let filter = Filter::In { field, values };
+            return Ok(if negated { Filter::Not(Box::new(filter)) } else { filter });
+        }
+        let comparison = match content.next() {
+            Some(Token::Punctuation(operator)) => Comparison::parse(&operator).ok_or(ParseError::UnexpectedToken { line: 0, col: 0, token: operator })?,
+            token => return Err(Command::unexpected_token(token)),
+        };
+        let value = Command::parse_literal(content.next())?;
+        Ok(Filter::Compare { field, comparison, value })
+    }
+
     fn parse_ref(
         content: &mut Peekable<impl Iterator<Item = Token>>,
     ) -> Result<AstRefData, ParseError> {
@@ -348,17 +575,13 @@ impl Command {
 
         let id_1 = tok.content().to_string();
 
-        if let Some(_) = content.next_if(|tok| tok.content().to_uppercase() == "INSIDE") {
-            let tok = content.next();
-            if tok.is_none() {
-                Err(ParseError::UnexpectedEOS)
-            } else {
-                let tok = tok.unwrap();
-                let id_2 = tok.content().to_string();
-                Ok(AstRefData {
+        if content.next_if(|tok| tok.content().to_uppercase() == "INSIDE").is_some() {
+            match content.next() {
+                None => Err(ParseError::UnexpectedEOS),
+                Some(tok) => Ok(AstRefData {
                     bucket: Some(id_1),
-                    database: id_2,
-                })
+                    database: tok.content().to_string(),
+                }),
             }
         } else {
             Ok(AstRefData {
@@ -368,6 +591,46 @@ impl Command {
         }
     }
 
+    /// Parses `DATABASE name` or `BUCKET name INSIDE database`.
+    fn parse_entity_ref(
+        content: &mut Peekable<impl Iterator<Item = Token>>,
+    ) -> Result<AstRefData, ParseError> {
+        let entity = Command::force_keyword(None, content.next())?;
+        match entity.as_str() {
+            "DATABASE" => {
+                let ref_ = Command::parse_ref(content)?;
+                if ref_.bucket.is_some() {
+                    return Err(ParseError::UnexpectedToken {
+                        line: 0,
+                        col: 0,
+                        token: "INSIDE".to_string(),
+                    });
+                }
+                Ok(ref_)
+            }
+            "BUCKET" => {
+                let ref_ = Command::parse_ref(content)?;
+                if ref_.bucket.is_none() {
+                    return if let Some(tok) = content.next() {
+                        Err(ParseError::UnexpectedToken {
+                            line: 0,
+                            col: 0,
+                            token: tok.content().to_string(),
+                        })
+                    } else {
+                        Err(ParseError::UnexpectedEOS)
+                    };
+                }
+                Ok(ref_)
+            }
+            tok => Err(ParseError::UnexpectedToken {
+                line: 0,
+                col: 0,
+                token: tok.to_string(),
+            }),
+        }
+    }
+
     fn force_keyword(name: Option<&str>, token: Option<Token>) -> Result<String, ParseError> {
         if token.is_none() {
             return Err(ParseError::UnexpectedEOS);
@@ -392,6 +655,28 @@ impl Command {
         Ok(token.content().to_string())
     }
 
+    /// Makes sure `token` is identifier `name`, ignoring case. Used for words that are not keywords
+    /// so that they can still name properties.
+    fn force_identifier(name: &str, token: Option<Token>) -> Result<(), ParseError> {
+        match token {
+            None => Err(ParseError::UnexpectedEOS),
+            Some(Token::Identifier(content)) if content.eq_ignore_ascii_case(name) => Ok(()),
+            Some(token) => Err(ParseError::UnexpectedToken {
+                line: 0,
+                col: 0,
+                token: token.content().to_string(),
+            }),
+        }
+    }
+
+    /// Error for `token` found where another one was expected.
+    fn unexpected_token(token: Option<Token>) -> ParseError {
+        match token {
+            None => ParseError::UnexpectedEOS,
+            Some(token) => ParseError::UnexpectedToken { line: 0, col: 0, token: token.content().to_string() },
+        }
+    }
+
     pub fn parse(content: &str) -> Result<Self, ParseError> {
         let mut tokens = vec![];
         {
@@ -399,7 +684,6 @@ impl Command {
             enum TokenType {
                 Keyword,
                 Identifier,
-                Punctuation,
                 Unknown,
                 Number,
             }
@@ -410,67 +694,87 @@ impl Command {
             // Tokenize command
             let mut buff = String::new();
             let mut token_type = TokenType::Unknown;
-            for c in content.chars() {
-                if token_type != TokenType::Punctuation {
-                    if buff.is_empty() && (c.is_alphabetic() || c == '_') {
-                        buff.push(c);
-                        token_type = TokenType::Identifier;
-                    } else if buff.is_empty() && c.is_numeric() || c == '-' {
-                        token_type = TokenType::Number;
-                        buff.push(c);
-                    } else if !buff.is_empty() && c.is_alphanumeric() || c == '_' {
-                        buff.push(c);
-                    } else if !buff.is_empty() && c.is_numeric() || c == '.' {
-                        if c == '.' && buff.contains(c) {
-                            return Err(ParseError::UnexpectedToken {
-                                line: line_counter,
-                                col: char_counter,
-                                token: c.to_string(),
-                            });
-                        }
-                        buff.push(c);
+            // Content of the string literal being read, if any.
+            let mut literal: Option<String> = None;
+            let mut chars = content.chars().peekable();
+            while let Some(c) = chars.next() {
+                if c == '\n' {
+                    line_counter += 1;
+                    char_counter = 0;
+                } else {
+                    char_counter += 1;
+                }
+                if let Some(string) = &mut literal {
+                    if c != '\'' || chars.next_if_eq(&'\'').is_some() {
+                        string.push(c);
                     } else {
-                        if KEYWORDS.contains(&buff.to_ascii_uppercase().as_str()) {
-                            token_type = TokenType::Keyword;
+                        tokens.push(Token::String(literal.take().expect("Literal is being read")));
+                    }
+                    continue;
+                }
+                if buff.is_empty() && (c.is_alphabetic() || c == '_') {
+                    buff.push(c);
+                    token_type = TokenType::Identifier;
+                } else if buff.is_empty() && c.is_numeric() || c == '-' {
+                    token_type = TokenType::Number;
+                    buff.push(c);
+                } else if !buff.is_empty() && c.is_alphanumeric() || c == '_' {
+                    buff.push(c);
+                } else if (!buff.is_empty() && c.is_numeric() || c == '.') && chars.peek() != Some(&'.') {
+                    if c == '.' && buff.contains(c) {
+                        return Err(ParseError::UnexpectedToken {
+                            line: line_counter,
+                            col: char_counter,
+                            token: c.to_string(),
+                        });
+                    }
+                    buff.push(c);
+                } else {
+                    if KEYWORDS.contains(&buff.to_ascii_uppercase().as_str()) {
+                        token_type = TokenType::Keyword;
+                    }
+                    match token_type {
+                        TokenType::Keyword => {
+                            tokens.push(Token::Keyword(buff.to_ascii_uppercase()))
                         }
-                        match token_type {
-                            TokenType::Keyword => {
-                                tokens.push(Token::Keyword(buff.to_ascii_uppercase()))
-                            }
-                            TokenType::Identifier => {
-                                if !buff.is_empty() {
-                                    tokens.push(Token::Identifier(buff.clone()))
-                                }
-                            }
-                            TokenType::Number => tokens.push(Token::Number(buff.clone())),
-                            _ => {}
+                        TokenType::Identifier if !buff.is_empty() => {
+                            tokens.push(Token::Identifier(buff.clone()))
                         }
-                        buff.clear();
-                        token_type = TokenType::Unknown;
-                        if ",.[](){}=;".contains(c) {
-                            tokens.push(Token::Punctuation(c.into()))
-                        } else if c.is_whitespace() {
-                            continue;
-                        } else {
-                            return Err(ParseError::UnexpectedToken {
-                                line: line_counter,
-                                col: char_counter,
-                                token: c.into(),
-                            });
+                        TokenType::Number => tokens.push(Token::Number(buff.clone())),
+                        _ => {}
+                    }
+                    buff.clear();
+                    token_type = TokenType::Unknown;
+                    if c == '\'' {
+                        literal = Some(String::new());
+                    } else if "<>!".contains(c) {
+                        let mut operator = c.to_string();
+                        if let Some(next) = chars.next_if(|&next| next == '=' || c == '<' && next == '>') {
+                            operator.push(next);
                         }
+                        tokens.push(Token::Punctuation(operator))
+                    } else if c == '.' && chars.next_if_eq(&'.').is_some() {
+                        tokens.push(Token::Punctuation("..".into()))
+                    } else if ",.[](){}=;".contains(c) {
+                        tokens.push(Token::Punctuation(c.into()))
+                    } else if c.is_whitespace() {
+                        continue;
+                    } else {
+                        return Err(ParseError::UnexpectedToken {
+                            line: line_counter,
+                            col: char_counter,
+                            token: c.into(),
+                        });
                     }
-                } else {
                 }
             }
+            if literal.is_some() {
+                return Err(ParseError::UnexpectedEOS);
+            }
         }
 
         // AST
         {
-            #[derive(Debug)]
-            enum EntityType {
-                Bucket,
-                Database,
-            }
             #[derive(Debug)]
             enum CommandPrototype {
                 Create {
@@ -481,13 +785,54 @@ impl Command {
                     ref_: AstRefData,
                     keys: AstVecData,
                     values: AstVecData,
+                    metadata: Option<Vec<Metadata>>,
+                    ids: Option<Vec<String>>,
                     with: AstWithClauseData,
                 },
+                Update {
+                    ref_: AstRefData,
+                    values: AstVecData,
+                    filter: Filter,
+                },
                 Scan {
                     ref_: AstRefData,
                     queries: AstVecData,
+                    filter: Option<Filter>,
+                    return_weights: bool,
+                    with: AstWithClauseData,
+                },
+                Search {
+                    ref_: AstRefData,
+                    queries: AstVecData,
+                    return_values: bool,
+                    with: AstWithClauseData,
+                },
+                Describe {
+                    ref_: AstRefData,
+                },
+                Compress {
+                    ref_: AstRefData,
+                    with: AstWithClauseData,
+                },
+                BuildIndex {
+                    ref_: AstRefData,
                     with: AstWithClauseData,
                 },
+                SetProjection {
+                    ref_: AstRefData,
+                    matrices: [Option<AstVecData>; 3],
+                },
+                Delete {
+                    ref_: AstRefData,
+                    filter: Filter,
+                },
+                Compact {
+                    ref_: AstRefData,
+                },
+                Get {
+                    ref_: AstRefData,
+                    entries: EntrySelection,
+                },
             }
 
             let mut token_iter = tokens.into_iter().peekable();
@@ -505,47 +850,94 @@ impl Command {
             }
             let command_prototype = match tok.content().to_uppercase().as_str() {
                 "CREATE" => {
-                    let entity = Command::force_keyword(None, token_iter.next())?;
-                    let ref_ = match entity.to_uppercase().as_str() {
-                        "DATABASE" => {
-                            let ref_ = Command::parse_ref(&mut token_iter)?;
-                            if ref_.bucket.is_some() {
-                                return Err(ParseError::UnexpectedToken {
-                                    line: 0,
-                                    col: 0,
-                                    token: "INSIDE".to_string(),
-                                });
-                            }
-                            ref_
+                    let ref_ = Command::parse_entity_ref(&mut token_iter)?;
+                    let with = Command::parse_with_clause(&mut token_iter)?;
+                    CommandPrototype::Create { ref_, with }
+                }
+                "DESCRIBE" => {
+                    let ref_ = Command::parse_entity_ref(&mut token_iter)?;
+                    CommandPrototype::Describe { ref_ }
+                }
+                "COMPRESS" => {
+                    Command::force_keyword(Some("BUCKET"), token_iter.next())?;
+                    let ref_ = Command::parse_ref(&mut token_iter)?;
+                    let with = Command::parse_with_clause(&mut token_iter)?;
+                    CommandPrototype::Compress { ref_, with }
+                }
+                "DELETE" => {
+                    Command::force_identifier("FROM", token_iter.next())?;
+                    let ref_ = Command::parse_ref(&mut token_iter)?;
+                    Command::force_keyword(Some("WHERE"), token_iter.next())?;
+                    let filter = Command::parse_filter(&mut token_iter)?;
+                    CommandPrototype::Delete { ref_, filter }
+                }
+                "COMPACT" => {
+                    Command::force_keyword(Some("BUCKET"), token_iter.next())?;
+                    let ref_ = Command::parse_ref(&mut token_iter)?;
+                    CommandPrototype::Compact { ref_ }
+                }
+                "GET" => {
+                    let ref_ = Command::parse_ref(&mut token_iter)?;
+                    let entries = match token_iter.next() {
+                        Some(Token::Identifier(selection)) if selection.eq_ignore_ascii_case("IDS") => {
+                            EntrySelection::Ids(Command::parse_entry_ids(&mut token_iter)?)
                         }
-                        "BUCKET" => {
-                            let ref_ = Command::parse_ref(&mut token_iter)?;
-                            if ref_.bucket.is_none() {
-                                let tok = token_iter.next();
-                                return if let Some(tok) = tok {
-                                    Err(ParseError::UnexpectedToken {
-                                        line: 0,
-                                        col: 0,
-                                        token: tok.content().to_string(),
-                                    })
-                                } else {
-                                    Err(ParseError::UnexpectedEOS)
-                                };
+                        Some(Token::Identifier(selection)) if selection.eq_ignore_ascii_case("RANGE") => {
+                            let start = Command::parse_entry_id(token_iter.next())?;
+                            match token_iter.next() {
+                                Some(Token::Punctuation(p)) if p == ".." => {}
+                                None => return Err(ParseError::UnexpectedEOS),
+                                Some(token) => return Err(ParseError::UnexpectedToken { line: 0, col: 0, token: token.content().to_string() }),
                             }
-                            ref_
-                        }
-                        tok => {
-                            return Err(ParseError::UnexpectedToken {
-                                line: 0,
-                                col: 0,
-                                token: tok.to_string(),
-                            })
+                            let end = Command::parse_entry_id(token_iter.next())?;
+                            if end < start {
+                                return Err(ParseError::InvalidRange { start, end });
+                            }
+                            EntrySelection::Range { start, end }
                         }
+                        None => return Err(ParseError::UnexpectedEOS),
+                        Some(token) => return Err(ParseError::UnexpectedToken { line: 0, col: 0, token: token.content().to_string() }),
                     };
+                    CommandPrototype::Get { ref_, entries }
+                }
+                "BUILD" => {
+                    Command::force_identifier("INDEX", token_iter.next())?;
+                    let ref_ = Command::parse_ref(&mut token_iter)?;
                     let with = Command::parse_with_clause(&mut token_iter)?;
-                    CommandPrototype::Create { ref_, with }
+                    CommandPrototype::BuildIndex { ref_, with }
+                }
+                "SET" => {
+                    Command::force_identifier("PROJECTION", token_iter.next())?;
+                    Command::force_identifier("FOR", token_iter.next())?;
+                    let ref_ = Command::parse_entity_ref(&mut token_iter)?;
+                    if ref_.bucket.is_some() {
+                        return Err(ParseError::UnexpectedToken { line: 0, col: 0, token: "BUCKET".to_string() });
+                    }
+                    let mut matrices: [Option<AstVecData>; 3] = [None, None, None];
+                    while let Some(Token::Identifier(name)) = token_iter.next_if(|tok| tok.ty() == "identifier") {
+                        let matrix = match name.to_uppercase().as_str() {
+                            "WQ" => &mut matrices[0],
+                            "WK" => &mut matrices[1],
+                            "WV" => &mut matrices[2],
+                            _ => return Err(ParseError::UnexpectedToken { line: 0, col: 0, token: name }),
+                        };
+                        if matrix.is_some() {
+                            return Err(ParseError::UnexpectedToken { line: 0, col: 0, token: name });
+                        }
+                        *matrix = Some(Command::parse_vec(&mut token_iter)?);
+                    }
+                    CommandPrototype::SetProjection { ref_, matrices }
                 }
-                "INSERT" => {
+                "UPDATE" => {
+                    let ref_ = Command::parse_ref(&mut token_iter)?;
+                    Command::force_keyword(Some("SET"), token_iter.next())?;
+                    Command::force_keyword(Some("VALUES"), token_iter.next())?;
+                    let values = Command::parse_vec(&mut token_iter)?;
+                    Command::force_keyword(Some("WHERE"), token_iter.next())?;
+                    let filter = Command::parse_filter(&mut token_iter)?;
+                    CommandPrototype::Update { ref_, values, filter }
+                }
+                operation @ ("INSERT" | "UPSERT") => {
                     let into = token_iter.next();
                     if into.is_none() {
                         return Err(ParseError::UnexpectedEOS);
@@ -563,11 +955,23 @@ impl Command {
                     let keys = Command::parse_vec(&mut token_iter)?;
                     Command::force_keyword(Some("VALUES"), token_iter.next())?;
                     let values = Command::parse_vec(&mut token_iter)?;
+                    let ids = if operation == "UPSERT" {
+                        Command::force_identifier("IDS", token_iter.next())?;
+                        Some(Command::parse_ids(&mut token_iter)?)
+                    } else {
+                        None
+                    };
+                    let metadata = match token_iter.next_if(|tok| tok.ty() == "identifier" && tok.content().eq_ignore_ascii_case("METADATA")) {
+                        Some(_) => Some(Command::parse_metadata(&mut token_iter)?),
+                        None => None,
+                    };
                     let with = Command::parse_with_clause(&mut token_iter)?;
                     CommandPrototype::Insert {
                         ref_,
                         keys,
                         values,
+                        metadata,
+                        ids,
                         with,
                     }
                 }
@@ -575,10 +979,33 @@ impl Command {
                     let ref_ = Command::parse_ref(&mut token_iter)?;
                     Command::force_keyword(Some("QUERIES"), token_iter.next())?;
                     let queries = Command::parse_vec(&mut token_iter)?;
+                    let filter = Command::parse_where_clause(&mut token_iter)?;
+                    let return_weights = token_iter.next_if(|tok| tok.ty() == "keyword" && tok.content() == "RETURN").is_some();
+                    if return_weights {
+                        Command::force_identifier("WEIGHTS", token_iter.next())?;
+                    }
                     let with = Command::parse_with_clause(&mut token_iter)?;
                     CommandPrototype::Scan {
                         ref_,
                         queries,
+                        filter,
+                        return_weights,
+                        with,
+                    }
+                }
+                "SEARCH" => {
+                    let ref_ = Command::parse_ref(&mut token_iter)?;
+                    Command::force_keyword(Some("QUERIES"), token_iter.next())?;
+                    let queries = Command::parse_vec(&mut token_iter)?;
+                    let return_values = token_iter.next_if(|tok| tok.ty() == "keyword" && tok.content() == "RETURN").is_some();
+                    if return_values {
+                        Command::force_keyword(Some("VALUES"), token_iter.next())?;
+                    }
+                    let with = Command::parse_with_clause(&mut token_iter)?;
+                    CommandPrototype::Search {
+                        ref_,
+                        queries,
+                        return_values,
                         with,
                     }
                 }
@@ -590,7 +1017,7 @@ impl Command {
                     })
                 }
             };
-            return Ok(match command_prototype {
+            Ok(match command_prototype {
                 CommandPrototype::Create { ref_, with } => match ref_.bucket {
                     None => Command::CreateDatabase {
                         name: ref_.database,
@@ -608,25 +1035,42 @@ impl Command {
                     ref_,
                     keys,
                     values,
+                    metadata,
+                    ids,
                     with,
                 } => {
                     if ref_.bucket.is_none() {
                         return Err(ParseError::NoBucketInInsert);
                     }
+                    let entries = keys.0.len().min(values.0.len());
+                    if let Some(metadata) = &metadata {
+                        if metadata.len() != entries {
+                            return Err(ParseError::CountMismatch { list: "Metadata", entries, got: metadata.len() });
+                        }
+                    }
+                    if let Some(ids) = &ids {
+                        if ids.len() != entries {
+                            return Err(ParseError::CountMismatch { list: "Ids", entries, got: ids.len() });
+                        }
+                    }
                     Command::Insert {
                         database: ref_.database,
                         bucket: ref_.bucket.unwrap(),
-                        entries: zip(keys.0.into_iter(), values.0.into_iter()).collect::<Vec<(
+                        entries: zip(keys.0, values.0).collect::<Vec<(
                             Vec<f32>,
                             Vec<f32>,
                         )>>(
                         ),
+                        metadata,
+                        ids,
                         properties: with.0,
                     }
                 }
                 CommandPrototype::Scan {
                     ref_,
                     queries,
+                    filter,
+                    return_weights,
                     with,
                 } => Command::Scan {
                     database: ref_.database,
@@ -642,9 +1086,69 @@ impl Command {
                         }
                     },
                     queries: queries.0,
+                    filter,
                     properties: with.0,
+                    return_weights,
                 },
-            });
+                CommandPrototype::Search {
+                    ref_,
+                    queries,
+                    return_values,
+                    with,
+                } => Command::Search {
+                    database: ref_.database,
+                    bucket: ref_.bucket.ok_or(ParseError::NoBucket)?,
+                    queries: queries.0,
+                    properties: with.0,
+                    return_values,
+                },
+                CommandPrototype::Describe { ref_ } => Command::Describe {
+                    database: ref_.database,
+                    bucket: ref_.bucket,
+                },
+                CommandPrototype::Compress { ref_, with } => Command::Compress {
+                    database: ref_.database,
+                    bucket: ref_.bucket.ok_or(ParseError::NoBucket)?,
+                    properties: with.0,
+                },
+                CommandPrototype::BuildIndex { ref_, with } => Command::BuildIndex {
+                    database: ref_.database,
+                    bucket: ref_.bucket.ok_or(ParseError::NoBucket)?,
+                    properties: with.0,
+                },
+                CommandPrototype::Update { ref_, values, filter } => {
+                    let mut values = values.0;
+                    if values.len() != 1 {
+                        return Err(ParseError::SingleVectorExpected(values.len()));
+                    }
+                    Command::Update {
+                        database: ref_.database,
+                        bucket: ref_.bucket.ok_or(ParseError::NoBucket)?,
+                        value: values.remove(0),
+                        filter,
+                    }
+                }
+                CommandPrototype::Delete { ref_, filter } => Command::Delete {
+                    database: ref_.database,
+                    bucket: ref_.bucket.ok_or(ParseError::NoBucket)?,
+                    filter,
+                },
+                CommandPrototype::Compact { ref_ } => Command::Compact {
+                    database: ref_.database,
+                    bucket: ref_.bucket.ok_or(ParseError::NoBucket)?,
+                },
+                CommandPrototype::Get { ref_, entries } => Command::Get {
+                    database: ref_.database,
+                    bucket: ref_.bucket.ok_or(ParseError::NoBucket)?,
+                    entries,
+                },
+                CommandPrototype::SetProjection { ref_, matrices: [wq, wk, wv] } => Command::SetProjection {
+                    database: ref_.database,
+                    wq: wq.map(|m| m.0),
+                    wk: wk.map(|m| m.0),
+                    wv: wv.map(|m| m.0),
+                },
+            })
         }
     }
 }
@@ -653,8 +1157,16 @@ pub fn parse_commands(content: &str) -> Result<Vec<Command>, ParseError> {
     let mut commands = vec![];
     let mut prev = String::new();
     let mut is_comment = false;
+    // Semicolons and slashes inside string literals are part of them.
+    let mut is_literal = false;
     for c in content.chars() {
-        if c == '/' && prev.ends_with('/') {
+        if c == '\'' && !is_comment {
+            // Two quotes in a row leave and enter the literal again
+            is_literal = !is_literal;
+            prev.push(c)
+        } else if is_literal {
+            prev.push(c)
+        } else if c == '/' && prev.ends_with('/') {
             // Comment starts with "//"
             is_comment = true;
             prev.remove(prev.len() - 1);
@@ -670,6 +1182,114 @@ pub fn parse_commands(content: &str) -> Result<Vec<Command>, ParseError> {
             prev.push(c)
         }
     }
+    if is_literal {
+        return Err(ParseError::UnexpectedEOS);
+    }
 
     Ok(commands)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses `filter` as the condition of a `DELETE`.
+    fn parse_filter(filter: &str) -> Result<Filter, ParseError> {
+        match parse_commands(&format!("DELETE FROM b INSIDE d WHERE {filter};"))?.pop() {
+            Some(Command::Delete { filter, .. }) => Ok(filter),
+            command => panic!("Expected DELETE, got {command:?}"),
+        }
+    }
+
+    fn filter(filter: &str) -> Filter {
+        parse_filter(filter).unwrap_or_else(|err| panic!("`{filter}` did not parse: {err}"))
+    }
+
+    fn compare(field: &str, comparison: Comparison, value: i64) -> Filter {
+        Filter::Compare { field: field.into(), comparison, value: Value::Integer(value) }
+    }
+
+    fn equal(field: &str, value: i64) -> Filter {
+        compare(field, Comparison::Equal, value)
+    }
+
+    fn and(a: Filter, b: Filter) -> Filter {
+        Filter::And(Box::new(a), Box::new(b))
+    }
+
+    fn or(a: Filter, b: Filter) -> Filter {
+        Filter::Or(Box::new(a), Box::new(b))
+    }
+
+    fn not(filter: Filter) -> Filter {
+        Filter::Not(Box::new(filter))
+    }
+
+    #[test]
+    fn or_binds_weaker_than_and() {
+        assert_eq!(filter("a = 1 OR b = 2 AND c = 3"), or(equal("a", 1), and(equal("b", 2), equal("c", 3))));
+        assert_eq!(filter("a = 1 AND b = 2 OR c = 3"), or(and(equal("a", 1), equal("b", 2)), equal("c", 3)));
+        assert_eq!(filter("(a = 1 OR b = 2) AND c = 3"), and(or(equal("a", 1), equal("b", 2)), equal("c", 3)));
+        assert_eq!(filter("a = 1 OR b = 2 OR c = 3"), or(or(equal("a", 1), equal("b", 2)), equal("c", 3)));
+        assert_eq!(filter("NOT a = 1 AND b = 2"), and(not(equal("a", 1)), equal("b", 2)));
+    }
+
+    #[test]
+    fn not_combines_with_in() {
+        let in_ = |field: &str, values: Vec<Value>| Filter::In { field: field.into(), values };
+        assert_eq!(filter("a NOT IN (1, 'x')"), not(in_("a", vec![Value::Integer(1), Value::String("x".into())])));
+        assert_eq!(filter("NOT a IN (1.5)"), not(in_("a", vec![Value::Float(1.5)])));
+        assert_eq!(filter("NOT a NOT IN (1)"), not(not(in_("a", vec![Value::Integer(1)]))));
+        assert_eq!(
+            filter("a NOT IN (1) OR b IN (2) AND c = 3"),
+            or(not(in_("a", vec![Value::Integer(1)])), and(in_("b", vec![Value::Integer(2)]), equal("c", 3))),
+        );
+    }
+
+    #[test]
+    fn comparison_operators() {
+        for (operator, comparison) in [
+            ("=", Comparison::Equal),
+            ("!=", Comparison::NotEqual),
+            ("<>", Comparison::NotEqual),
+            ("<", Comparison::Less),
+            ("<=", Comparison::LessOrEqual),
+            (">", Comparison::Greater),
+            (">=", Comparison::GreaterOrEqual),
+        ] {
+            assert_eq!(filter(&format!("a {operator} 1")), compare("a", comparison, 1));
+            assert_eq!(filter(&format!("a{operator}1")), compare("a", comparison, 1));
+        }
+    }
+
+    #[test]
+    fn malformed_filters_are_errors() {
+        for malformed in [
+            "",
+            "a",
+            "a =",
+            "= 1",
+            "a 1",
+            "a == 1",
+            "a =< 1",
+            "a >< 1",
+            "a = 1.2.3",
+            "a = 'x",
+            "a IN",
+            "a IN 1",
+            "a IN ()",
+            "a IN (1,)",
+            "a IN (1, 2",
+            "a NOT",
+            "a NOT = 1",
+            "NOT",
+            "(a = 1",
+            "()",
+            "a = 1 AND",
+            "a = 1 OR OR b = 2",
+            "AND a = 1",
+        ] {
+            assert!(parse_filter(malformed).is_err(), "`{malformed}` parsed");
+        }
+    }
+}
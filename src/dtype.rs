@@ -0,0 +1,94 @@
+use half::{bf16, f16};
+use ndarray::{ArrayView2, CowArray, Ix2};
+use serde::{Deserialize, Serialize};
+use std::mem::size_of;
+
+/// Type keys and values are stored as inside bucket files.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DType {
+    #[default]
+    F32,
+    F16,
+    BF16,
+}
+
+impl DType {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "f32" => Some(DType::F32),
+            "f16" => Some(DType::F16),
+            "bf16" => Some(DType::BF16),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            DType::F32 => "f32",
+            DType::F16 => "f16",
+            DType::BF16 => "bf16",
+        }
+    }
+
+    /// Size of a single element in bytes.
+    pub fn size(&self) -> usize {
+        match self {
+            DType::F32 => size_of::<f32>(),
+            DType::F16 => size_of::<f16>(),
+            DType::BF16 => size_of::<bf16>(),
+        }
+    }
+}
+
+/// Element of the bucket files, convertible from and to `f32` used by the attention kernel.
+pub trait StoredElement: Copy + Send + Sync + 'static {
+    fn from_f32(value: f32) -> Self;
+
+    fn to_f32(self) -> f32;
+
+    /// Converts a batch of stored elements to `f32`, borrowing it when no conversion is needed.
+    fn widen(view: ArrayView2<'_, Self>) -> CowArray<'_, f32, Ix2>;
+}
+
+impl StoredElement for f32 {
+    fn from_f32(value: f32) -> Self {
+        value
+    }
+
+    fn to_f32(self) -> f32 {
+        self
+    }
+
+    fn widen(view: ArrayView2<'_, Self>) -> CowArray<'_, f32, Ix2> {
+        view.into()
+    }
+}
+
+impl StoredElement for f16 {
+    fn from_f32(value: f32) -> Self {
+        f16::from_f32(value)
+    }
+
+    fn to_f32(self) -> f32 {
+        f16::to_f32(self)
+    }
+
+    fn widen(view: ArrayView2<'_, Self>) -> CowArray<'_, f32, Ix2> {
+        view.mapv(f16::to_f32).into()
+    }
+}
+
+impl StoredElement for bf16 {
+    fn from_f32(value: f32) -> Self {
+        bf16::from_f32(value)
+    }
+
+    fn to_f32(self) -> f32 {
+        bf16::to_f32(self)
+    }
+
+    fn widen(view: ArrayView2<'_, Self>) -> CowArray<'_, f32, Ix2> {
+        view.mapv(bf16::to_f32).into()
+    }
+}
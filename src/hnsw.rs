@@ -0,0 +1,246 @@
+use serde::{Deserialize, Serialize};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BTreeSet, BinaryHeap, HashMap, HashSet};
+use std::mem::size_of;
+
+/// Parameters of the HNSW graph of a bucket.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HnswConfiguration {
+    /// Maximum number of neighbours of a node on upper layers, twice as many are kept on the bottom layer.
+    pub m: usize,
+    /// Number of candidates considered while linking a new node.
+    pub ef_construction: usize,
+}
+
+impl Default for HnswConfiguration {
+    fn default() -> Self {
+        Self { m: 16, ef_construction: 100 }
+    }
+}
+
+/// Vectors the graph is built over, addressed by entry index.
+pub trait Vectors {
+    /// Dot product of `q` with vector of entry `row`.
+    fn dot(&self, q: &[f32], row: usize) -> f32;
+
+    fn vector(&self, row: usize) -> Vec<f32>;
+}
+
+/// Node along with its similarity to the vector being searched for.
+#[derive(Debug, Copy, Clone)]
+struct Scored {
+    score: f32,
+    node: u32,
+}
+
+impl PartialEq for Scored {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Scored {}
+
+impl PartialOrd for Scored {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scored {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.score.total_cmp(&other.score).then(self.node.cmp(&other.node))
+    }
+}
+
+/// Upper layers of the graph, small enough to be rewritten on every save.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct UpperLayers {
+    entry_point: Option<u32>,
+    max_level: usize,
+    /// Neighbours of nodes present above the bottom layer, `nodes[node][level - 1]`.
+    nodes: HashMap<u32, Vec<Vec<u32>>>,
+}
+
+/// Hierarchical navigable small world graph over keys, searched by dot product.
+/// Nodes are numbered by entry index and are inserted in that order.
+pub struct HnswIndex {
+    conf: HnswConfiguration,
+    /// Bottom layer, [`HnswIndex::slot_len`] values per node: number of neighbours followed by the neighbours.
+    level0: Vec<u32>,
+    upper: UpperLayers,
+    /// Nodes whose bottom layer slot changed since the last call to [`HnswIndex::take_dirty`].
+    dirty: BTreeSet<u32>,
+}
+
+impl HnswIndex {
+    pub fn new(conf: HnswConfiguration) -> Self {
+        Self { conf, level0: vec![], upper: UpperLayers::default(), dirty: BTreeSet::new() }
+    }
+
+    /// Restores the graph from its bottom layer slots and upper layers written earlier.
+    pub fn from_parts(conf: HnswConfiguration, level0: Vec<u32>, upper: UpperLayers) -> Option<Self> {
+        let index = Self { conf, level0, upper, dirty: BTreeSet::new() };
+        if !index.level0.len().is_multiple_of(index.slot_len()) {
+            return None;
+        }
+        Some(index)
+    }
+
+    /// Number of values in a bottom layer slot of a single node.
+    pub fn slot_len(&self) -> usize {
+        2 * self.conf.m + 1
+    }
+
+    /// Size in bytes of a bottom layer slot of a single node.
+    pub fn slot_size(&self) -> usize {
+        self.slot_len() * size_of::<u32>()
+    }
+
+    pub fn len(&self) -> usize {
+        self.level0.len() / self.slot_len()
+    }
+
+    pub fn slot(&self, node: u32) -> &[u32] {
+        let start = node as usize * self.slot_len();
+        &self.level0[start..start + self.slot_len()]
+    }
+
+    pub fn upper(&self) -> &UpperLayers {
+        &self.upper
+    }
+
+    /// Returns nodes whose bottom layer slots have to be written, in ascending order.
+    pub fn take_dirty(&mut self) -> BTreeSet<u32> {
+        std::mem::take(&mut self.dirty)
+    }
+
+    /// Level of the highest layer `node` is present on. Levels are derived from the node number,
+    /// so the graph is the same every time entries are inserted in the same order.
+    fn level_of(&self, node: u32) -> usize {
+        let mut x = (node as u64).wrapping_add(0x9e3779b97f4a7c15);
+        x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+        x ^= x >> 31;
+        let uniform = (x >> 11) as f64 / (1u64 << 53) as f64;
+        (-(1. - uniform).ln() / (self.conf.m as f64).ln()).floor() as usize
+    }
+
+    fn max_neighbours(&self, level: usize) -> usize {
+        if level == 0 { 2 * self.conf.m } else { self.conf.m }
+    }
+
+    fn neighbours(&self, node: u32, level: usize) -> &[u32] {
+        if level == 0 {
+            let slot = self.slot(node);
+            &slot[1..1 + slot[0] as usize]
+        } else {
+            &self.upper.nodes[&node][level - 1]
+        }
+    }
+
+    fn set_neighbours(&mut self, node: u32, level: usize, neighbours: &[u32]) {
+        if level == 0 {
+            let slot_len = self.slot_len();
+            let start = node as usize * slot_len;
+            let slot = &mut self.level0[start..start + slot_len];
+            slot.fill(0);
+            slot[0] = neighbours.len() as u32;
+            slot[1..1 + neighbours.len()].copy_from_slice(neighbours);
+            self.dirty.insert(node);
+        } else {
+            self.upper.nodes.get_mut(&node).expect("Node is present on the level")[level - 1] = neighbours.to_vec();
+        }
+    }
+
+    /// Adds the next entry to the graph.
+    pub fn insert(&mut self, vectors: &impl Vectors) {
+        let node = self.len() as u32;
+        let level = self.level_of(node);
+        self.level0.resize(self.level0.len() + self.slot_len(), 0);
+        self.dirty.insert(node);
+        if level > 0 {
+            self.upper.nodes.insert(node, vec![vec![]; level]);
+        }
+        let Some(mut entry_point) = self.upper.entry_point else {
+            self.upper.entry_point = Some(node);
+            self.upper.max_level = level;
+            return;
+        };
+        let q = vectors.vector(node as usize);
+        for l in (level + 1..=self.upper.max_level).rev() {
+            entry_point = self.search_layer(vectors, &q, entry_point, 1, l)[0].node;
+        }
+        for l in (0..=level.min(self.upper.max_level)).rev() {
+            let candidates = self.search_layer(vectors, &q, entry_point, self.conf.ef_construction, l);
+            let neighbours: Vec<u32> = candidates.iter().take(self.max_neighbours(l)).map(|c| c.node).collect();
+            self.set_neighbours(node, l, &neighbours);
+            for &neighbour in &neighbours {
+                self.link(vectors, neighbour, node, l);
+            }
+            entry_point = candidates[0].node;
+        }
+        if level > self.upper.max_level {
+            self.upper.entry_point = Some(node);
+            self.upper.max_level = level;
+        }
+    }
+
+    /// Adds `node` to neighbours of `to`, dropping the least similar one if there are too many.
+    fn link(&mut self, vectors: &impl Vectors, to: u32, node: u32, level: usize) {
+        let mut neighbours = self.neighbours(to, level).to_vec();
+        neighbours.push(node);
+        if neighbours.len() > self.max_neighbours(level) {
+            let v = vectors.vector(to as usize);
+            let mut scored: Vec<Scored> = neighbours.iter().map(|&n| Scored { score: vectors.dot(&v, n as usize), node: n }).collect();
+            scored.sort_unstable_by(|a, b| b.cmp(a));
+            neighbours = scored.iter().take(self.max_neighbours(level)).map(|s| s.node).collect();
+        }
+        self.set_neighbours(to, level, &neighbours);
+    }
+
+    /// Returns up to `ef` nodes of `level` most similar to `q`, best first.
+    fn search_layer(&self, vectors: &impl Vectors, q: &[f32], entry_point: u32, ef: usize, level: usize) -> Vec<Scored> {
+        let entry = Scored { score: vectors.dot(q, entry_point as usize), node: entry_point };
+        let mut visited = HashSet::from([entry_point]);
+        let mut candidates = BinaryHeap::from([entry]);
+        let mut best = BinaryHeap::from([Reverse(entry)]);
+        while let Some(candidate) = candidates.pop() {
+            if best.len() >= ef && best.peek().is_some_and(|worst| candidate.score < worst.0.score) {
+                break;
+            }
+            for &neighbour in self.neighbours(candidate.node, level) {
+                if !visited.insert(neighbour) {
+                    continue;
+                }
+                let scored = Scored { score: vectors.dot(q, neighbour as usize), node: neighbour };
+                if best.len() < ef || best.peek().is_some_and(|worst| scored.score > worst.0.score) {
+                    candidates.push(scored);
+                    best.push(Reverse(scored));
+                    if best.len() > ef {
+                        best.pop();
+                    }
+                }
+            }
+        }
+        let mut best: Vec<Scored> = best.into_iter().map(|s| s.0).collect();
+        best.sort_unstable_by(|a, b| b.cmp(a));
+        best
+    }
+
+    /// Returns entries with approximately the `k` highest dot products with `q`, best first,
+    /// considering `ef` candidates on the bottom layer.
+    pub fn search(&self, vectors: &impl Vectors, q: &[f32], k: usize, ef: usize) -> Vec<usize> {
+        let Some(mut entry_point) = self.upper.entry_point else {
+            return vec![];
+        };
+        for l in (1..=self.upper.max_level).rev() {
+            entry_point = self.search_layer(vectors, q, entry_point, 1, l)[0].node;
+        }
+        self.search_layer(vectors, q, entry_point, ef.max(k), 0)
+            .into_iter()
+            .take(k)
+            .map(|s| s.node as usize)
+            .collect()
+    }
+}
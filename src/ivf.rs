@@ -0,0 +1,81 @@
+use crate::clustering::{kmeans, nearest_centroids};
+use ndarray::{Array2, ArrayView1, ArrayView2};
+use serde::{Deserialize, Serialize};
+use std::mem::size_of;
+
+/// Parameters of the inverted file index of a bucket.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IvfConfiguration {
+    /// Number of clusters entries are split into.
+    pub lists: usize,
+}
+
+/// Inverted file index: keys are clustered and every entry is kept in the list of its nearest centroid,
+/// so that a scan can visit only the lists whose centroids have the highest dot products with the query.
+pub struct IvfIndex {
+    centroids: Array2<f32>,
+    /// Entries of every list in insertion order.
+    lists: Vec<Vec<u32>>,
+    len: usize,
+}
+
+impl IvfIndex {
+    /// Clusters `keys` into `lists` lists by running `iterations` of k-means.
+    /// Fewer lists are created if there are less keys than requested lists.
+    pub fn train(keys: ArrayView2<f32>, lists: usize, iterations: usize) -> Self {
+        let centroids = kmeans(keys, lists.min(keys.nrows()).max(1), iterations);
+        Self { lists: vec![vec![]; centroids.nrows()], centroids, len: 0 }
+    }
+
+    /// Restores the index from centroids written by [`IvfIndex::centroids_to_bytes`] and list of every entry.
+    pub fn from_parts(key_size: usize, centroids: &[u8], assignments: &[u32]) -> Option<Self> {
+        let values: Vec<f32> = centroids.chunks_exact(size_of::<f32>()).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect();
+        let centroids = Array2::from_shape_vec((values.len() / key_size, key_size), values).ok()?;
+        let mut index = Self { lists: vec![vec![]; centroids.nrows()], centroids, len: 0 };
+        if assignments.iter().any(|&list| list as usize >= index.lists.len()) {
+            return None;
+        }
+        index.add(assignments);
+        Some(index)
+    }
+
+    pub fn configuration(&self) -> IvfConfiguration {
+        IvfConfiguration { lists: self.lists.len() }
+    }
+
+    pub fn centroids_to_bytes(&self) -> Vec<u8> {
+        self.centroids.iter().flat_map(|x| x.to_le_bytes()).collect()
+    }
+
+    /// Number of entries in the index.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns list of every key, the one with the nearest centroid.
+    pub fn assign(&self, keys: ArrayView2<f32>) -> Vec<u32> {
+        nearest_centroids(keys, self.centroids.view()).into_iter().map(|list| list as u32).collect()
+    }
+
+    /// Appends the next entries, given the list of each of them.
+    pub fn add(&mut self, assignments: &[u32]) {
+        for &list in assignments {
+            self.lists[list as usize].push(self.len as u32);
+            self.len += 1;
+        }
+    }
+
+    /// Returns `nprobe` lists whose centroids have the highest dot products with `q`.
+    pub fn probe(&self, q: ArrayView1<f32>, nprobe: usize) -> Vec<usize> {
+        let scores = self.centroids.dot(&q);
+        let mut lists: Vec<usize> = (0..self.lists.len()).collect();
+        lists.sort_unstable_by(|&a, &b| scores[b].total_cmp(&scores[a]));
+        lists.truncate(nprobe);
+        lists
+    }
+
+    /// Entries of list number `list`, in ascending order.
+    pub fn list(&self, list: usize) -> &[u32] {
+        &self.lists[list]
+    }
+}
@@ -1,38 +1,40 @@
+mod attention;
+mod clustering;
 mod command;
+mod dtype;
+mod hnsw;
+mod ivf;
+mod metadata;
+mod projection;
+mod quantization;
 mod storage;
 
 use clap::Parser;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::fmt::{Display, Formatter, write};
-use std::io::{Error, SeekFrom, Write};
-use std::ops::{Add, Not};
+use std::fmt::{Display, Formatter};
+use std::mem::size_of;
 use std::path::PathBuf;
 use std::sync::Arc;
-use ndarray::{Array2, Axis};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use half::{bf16, f16};
+use ndarray::{s, Array2, ArrayView2};
+use rayon::ThreadPoolBuildError;
 use thiserror::Error;
-use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
-use tokio::sync::RwLock;
-use crate::command::{Command, ParseError, PropertyValue, ScanTargetBucket};
-use crate::storage::{AlreadyInUse, DatabaseConfiguration, Storage};
-use ndarray::prelude::*;
-use tokio::net::TcpListener;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use crate::attention::{Contribution, Metric, NearestNeighbours, Neighbour, Partial, PartialAttention, Position, Positions, RescoringAttention, ScanExecutor, ScanShape, Score, Scoring, TopKAttention};
+use crate::command::{Command, EntrySelection, PropertyList, PropertyValue, ScanTargetBucket};
+use crate::dtype::{DType, StoredElement};
+use crate::hnsw::HnswConfiguration;
+use crate::ivf::IvfIndex;
+use crate::metadata::{Filter, Metadata};
+use crate::projection::{Matrix, Projection};
+use crate::quantization::{dequantize_int8, KeyQuantization, ProductQuantizer};
+use crate::storage::{Bucket, BucketConfiguration, Compaction, DatabaseConfiguration, Eviction, InvalidLayoutError, KeySource, Storage, StorageError};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::MissedTickBehavior;
 
 extern crate blas_src;
 
-fn compute_cross_attention<'a>(qkv_vec_size: usize, q: &'a Array2<f32>) -> impl Fn(&mut Array2<f32>, &[f32], &[f32]) + 'a {
-    return move |mut acc: &mut Array2<f32>, k: &[f32], v: &[f32]| {
-        let k_vec: Vec<f32> = Vec::from(k);
-        let v_vec: Vec<f32> = Vec::from(v);
-        let k: Array2<f32> = Array2::from_shape_vec((k_vec.len() / qkv_vec_size, qkv_vec_size), k_vec).unwrap();  // 1x4
-        let v: Array2<f32> = Array2::from_shape_vec((v_vec.len() / qkv_vec_size, qkv_vec_size), v_vec).unwrap();  // 1x4
-        let e_scores = q.dot(&k.t()).mapv(|x| x.exp());
-        let scores = e_scores.clone() / e_scores.sum_axis(Axis(0));
-        let re_ = scores.dot(&v);
-        *acc = re_.add(acc.clone() / qkv_vec_size as f32);
-    };
-}
-
 #[derive(Debug, Copy, Clone)]
 pub enum EntityType {
     Database,
@@ -54,8 +56,8 @@ pub enum ExecutionError {
     DatabaseDoesNotExist { database: String },
     #[error("Bucket '{bucket}' does not exist inside database '{database}'")]
     BucketDoesNotExist { database: String, bucket: String },
-    #[error("Size of received vector does not match the configured database's : ")]
-    SizeMismatch { expected: u32, got: u32 },
+    #[error("Size of received {vector} does not match the configured database's: expected {expected}, got {got}")]
+    SizeMismatch { vector: &'static str, expected: u32, got: u32 },
     #[error("Entity {name} of type {ty} already exists")]
     EntityAlreadyExists {
         name: String,
@@ -67,258 +69,1497 @@ pub enum ExecutionError {
         found: &'static str,
         property: &'static str,
     },
+    #[error("Property {property} can not be `{value}`, expected {expected}")]
+    InvalidPropertyValue {
+        property: &'static str,
+        value: String,
+        expected: &'static str,
+    },
+    #[error("Property {property} can only be used {context}")]
+    PropertyNotApplicable {
+        property: &'static str,
+        context: &'static str,
+    },
+    #[error("Bucket '{bucket}' does not keep {keys} keys")]
+    MissingKeys { bucket: String, keys: &'static str },
+    #[error("Bucket '{bucket}' has no index")]
+    MissingIndex { bucket: String },
+    #[error("Entry {id} does not exist inside bucket '{bucket}'")]
+    EntryDoesNotExist { bucket: String, id: usize },
+    #[error("Bucket '{bucket}' is empty")]
+    EmptyBucket { bucket: String },
+    #[error("Scan requires {required} bytes of memory, but scan memory budget is {budget} bytes")]
+    MemoryBudgetExceeded { required: usize, budget: usize },
+    #[error("Matrix {matrix} must have at least one row and all of its rows must have the same size")]
+    InvalidMatrix { matrix: &'static str },
+    #[error("Projection matrices have version {current}, but version {expected} was requested")]
+    ProjectionVersionMismatch { expected: u32, current: u32 },
+    #[error("Scanning `{bucket}` bucket is not supported yet")]
+    UnsupportedBucket { bucket: &'static str },
+    #[error("{0}")]
+    StorageError(Arc<StorageError>),
 }
 
-#[derive(Parser, Debug)]
-pub struct Args {
-    #[arg(
-    long,
-    default_value = "./qkv-config.json",
-    long_help = "Path to database configuration file."
-    )]
-    pub config: PathBuf,
-    #[arg(long, default_value = None, long_help = "Path to command list that will be executed during initialization.")]
-    pub init: Option<PathBuf>,
+impl From<StorageError> for ExecutionError {
+    fn from(value: StorageError) -> Self {
+        Self::StorageError(Arc::new(value))
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, Default)]
-pub struct Configuration {
-    data_directory: PathBuf,
+/// Result of a successfully executed command.
+#[derive(Debug, Clone)]
+pub enum Output {
+    /// One vector per query.
+    Vectors(Vec<Vec<f32>>),
+    /// Named properties of an entity.
+    Properties(Vec<(&'static str, String)>),
+    /// One vector per query along with the entries that contributed the most to it and entropy of its attention weights.
+    /// Contributions and entropy are listed per query and head, heads of a query being adjacent.
+    /// Metadata of the contributing entries is listed if the bucket has any.
+    Weights {
+        vectors: Vec<Vec<f32>>,
+        contributions: Vec<Vec<Contribution>>,
+        entropy: Vec<f32>,
+        metadata: Option<Vec<Vec<Metadata>>>,
+    },
+    /// Entries found for every query, best first. Values are listed only if `values` is set,
+    /// metadata if the bucket has any.
+    Neighbours {
+        neighbours: Vec<Vec<Neighbour>>,
+        values: bool,
+        metadata: Option<Vec<Vec<Metadata>>>,
+    },
+    /// Keys and values of entries read by `GET`, metadata if the bucket has any.
+    Entries {
+        ids: Vec<usize>,
+        keys: Vec<Vec<f32>>,
+        values: Vec<Vec<f32>>,
+        metadata: Option<Vec<Metadata>>,
+    },
 }
 
-pub enum Bucket<'a> {
-    Hot,
-    Name(&'a str),
-    All,
+/// Formats a list as `[a, b]`.
+fn format_list<T: ToString>(list: &[T]) -> String {
+    format!("[{}]", list.iter().map(|r| r.to_string()).collect::<Vec<String>>().join(", "))
 }
 
-pub struct Database {
-    /// Size of the individual q, k or v vector in the database.
-    vec_size: u32,
-    /// Buckets that exist in this database besides two virtual ones (`hot` and `all`)
-    buckets: Vec<String>,
-    /// Directory containing database files
-    data_directory: PathBuf,
+/// Formats one list per query as `([a, b], [c, d])`.
+fn format_lists<T: ToString>(lists: &[Vec<T>]) -> String {
+    format!("({})", lists.iter().map(|v| format_list(v)).collect::<Vec<String>>().join(", "))
 }
 
-impl Database {
-    /// Scan the bucket `b`.
-    /// Takes in query and returns attended result across bucket.
-    pub fn scan(&self, q: Vec<f32>, b: Bucket<'_>) -> Vec<f32> {
-        todo!()
+impl Display for Output {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Output::Vectors(vectors) => {
+                writeln!(f, "{}", format_lists(vectors))
+            }
+            Output::Properties(properties) => {
+                for (name, value) in properties {
+                    writeln!(f, "{name} = {value}")?;
+                }
+                Ok(())
+            }
+            Output::Weights { vectors, contributions, entropy, metadata } => {
+                let field = |value: fn(&Contribution) -> String| -> String {
+                    format_lists(&contributions.iter().map(|c| c.iter().map(value).collect()).collect::<Vec<Vec<String>>>())
+                };
+                writeln!(f, "{}", format_lists(vectors))?;
+                writeln!(f, "ids = {}", field(|c| c.row.to_string()))?;
+                writeln!(f, "logits = {}", field(|c| c.logit.to_string()))?;
+                writeln!(f, "weights = {}", field(|c| c.weight.to_string()))?;
+                writeln!(f, "entropy = {}", format_list(entropy))?;
+                if let Some(metadata) = metadata {
+                    writeln!(f, "metadata = {}", format_lists(metadata))?;
+                }
+                Ok(())
+            }
+            Output::Neighbours { neighbours, values, metadata } => {
+                let field = |value: fn(&Neighbour) -> String| -> String {
+                    format_lists(&neighbours.iter().map(|n| n.iter().map(value).collect()).collect::<Vec<Vec<String>>>())
+                };
+                writeln!(f, "ids = {}", field(|n| n.row.to_string()))?;
+                writeln!(f, "scores = {}", field(|n| n.score.to_string()))?;
+                if *values {
+                    writeln!(f, "values = {}", field(|n| format_list(&n.value.to_vec())))?;
+                }
+                if let Some(metadata) = metadata {
+                    writeln!(f, "metadata = {}", format_lists(metadata))?;
+                }
+                Ok(())
+            }
+            Output::Entries { ids, keys, values, metadata } => {
+                writeln!(f, "ids = {}", format_list(ids))?;
+                writeln!(f, "keys = {}", format_lists(keys))?;
+                writeln!(f, "values = {}", format_lists(values))?;
+                if let Some(metadata) = metadata {
+                    writeln!(f, "metadata = {}", format_list(metadata))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Index a scan finds entries through.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum ScanIndex {
+    /// Every entry of the bucket is scanned.
+    None,
+    /// Entries with approximately the highest logits are found through the HNSW graph, requires `top_k`.
+    Hnsw,
+    /// Only entries of the inverted file lists closest to every query are scanned.
+    Ivf,
+}
+
+/// Options of a scan given by the client.
+#[derive(Debug, Default, Copy, Clone)]
+struct ScanOptions {
+    /// Overrides batch size derived from the scan memory budget.
+    batch_rows: Option<usize>,
+    /// Keys logits are computed from.
+    source: KeySource,
+    /// Number of entries per query whose logits are recomputed from exact keys after scanning quantized keys.
+    rescore: usize,
+    /// Restricts softmax to `top_k` entries with the highest logits among the scanned ones.
+    top_k: Option<usize>,
+    /// Index given by `index` property or implied by the other options.
+    /// If none is given, the HNSW graph serves `top_k` scans of buckets that have one.
+    index: Option<ScanIndex>,
+    /// Number of candidates considered by the HNSW search, at least `top_k`.
+    ef_search: Option<usize>,
+    /// Number of inverted file lists scanned for every query.
+    nprobe: Option<usize>,
+    /// Number of entries with the highest logits returned for every query along with their weights.
+    weights: Option<usize>,
+    /// Overrides score of the database.
+    score: Option<Score>,
+    /// Overrides temperature of the database.
+    temperature: Option<f32>,
+    /// Overrides number of heads of the database.
+    heads: Option<usize>,
+    /// Overrides position mode of the bucket.
+    position: Option<Position>,
+}
+
+impl ScanOptions {
+    fn from_properties(properties: &PropertyList, return_weights: bool) -> Result<Self, ExecutionError> {
+        let batch_rows = get_positive_integer_property(properties, "batch_rows")?.map(|x| x as usize);
+        let source = match get_string_property(properties, "key_precision")? {
+            None => KeySource::Exact,
+            Some(name) => match name.to_lowercase().as_str() {
+                "exact" => KeySource::Exact,
+                "int8" => KeySource::Int8,
+                "pq" => KeySource::Pq,
+                _ => return Err(ExecutionError::InvalidPropertyValue {
+                    property: "key_precision",
+                    value: name.to_string(),
+                    expected: "one of exact, int8, pq",
+                }),
+            },
+        };
+        let rescore = get_positive_integer_property(properties, "rescore")?.map_or(0, |x| x as usize);
+        if rescore > 0 && source == KeySource::Exact {
+            return Err(ExecutionError::PropertyNotApplicable { property: "rescore", context: "with key_precision = int8 or pq" });
+        }
+        let top_k = get_positive_integer_property(properties, "top_k")?.map(|x| x as usize);
+        if top_k.is_some() && source != KeySource::Exact {
+            return Err(ExecutionError::PropertyNotApplicable { property: "top_k", context: "with key_precision = exact" });
+        }
+        let mut index = match get_string_property(properties, "index")? {
+            None => None,
+            Some(name) => match name.to_lowercase().as_str() {
+                "none" => Some(ScanIndex::None),
+                "hnsw" => Some(ScanIndex::Hnsw),
+                "ivf" => Some(ScanIndex::Ivf),
+                _ => return Err(ExecutionError::InvalidPropertyValue { property: "index", value: name.to_string(), expected: "one of none, hnsw, ivf" }),
+            },
+        };
+        let ef_search = get_positive_integer_property(properties, "ef_search")?.map(|x| x as usize);
+        if ef_search.is_some() {
+            if index.is_some_and(|index| index != ScanIndex::Hnsw) {
+                return Err(ExecutionError::PropertyNotApplicable { property: "ef_search", context: "with index = hnsw" });
+            }
+            index = Some(ScanIndex::Hnsw);
+        }
+        let nprobe = get_positive_integer_property(properties, "nprobe")?.map(|x| x as usize);
+        if nprobe.is_some() {
+            if index.is_some_and(|index| index != ScanIndex::Ivf) {
+                return Err(ExecutionError::PropertyNotApplicable { property: "nprobe", context: "with index = ivf" });
+            }
+            index = Some(ScanIndex::Ivf);
+        }
+        match index {
+            Some(ScanIndex::Hnsw) if top_k.is_none() => {
+                return Err(ExecutionError::PropertyNotApplicable { property: "index = hnsw", context: "together with top_k" });
+            }
+            Some(ScanIndex::Hnsw | ScanIndex::Ivf) if source != KeySource::Exact => {
+                return Err(ExecutionError::PropertyNotApplicable { property: "index", context: "with key_precision = exact" });
+            }
+            _ => {}
+        }
+        let weights_limit = get_positive_integer_property(properties, "weights_limit")?.map(|x| x as usize);
+        if weights_limit.is_some() && !return_weights {
+            return Err(ExecutionError::PropertyNotApplicable { property: "weights_limit", context: "with RETURN WEIGHTS" });
+        }
+        // By default all entries of a top-k scan are returned.
+        let weights = return_weights.then(|| weights_limit.or(top_k).unwrap_or(DEFAULT_WEIGHTS_LIMIT));
+        let score = get_score_property(properties)?;
+        let temperature = get_positive_float_property(properties, "temperature")?;
+        let heads = get_positive_integer_property(properties, "heads")?.map(|x| x as usize);
+        let position = get_position_property(properties)?;
+        Ok(Self { batch_rows, source, rescore, top_k, index, ef_search, nprobe, weights, score, temperature, heads, position })
+    }
+}
+
+/// Options of a nearest neighbour search given by the client.
+#[derive(Debug, Copy, Clone)]
+struct SearchOptions {
+    /// Number of entries returned for every query.
+    k: usize,
+    metric: Metric,
+    /// Overrides batch size derived from the scan memory budget.
+    batch_rows: Option<usize>,
+    /// Whether values of the found entries are returned.
+    values: bool,
+}
+
+impl SearchOptions {
+    fn from_properties(properties: &PropertyList, return_values: bool) -> Result<Self, ExecutionError> {
+        let k = get_positive_integer_property(properties, "k")?.map_or(DEFAULT_SEARCH_K, |x| x as usize);
+        let metric = match get_string_property(properties, "metric")? {
+            None => Metric::default(),
+            Some(name) => Metric::parse(name).ok_or_else(|| ExecutionError::InvalidPropertyValue {
+                property: "metric",
+                value: name.to_string(),
+                expected: "one of dot, cosine, l2",
+            })?,
+        };
+        let batch_rows = get_positive_integer_property(properties, "batch_rows")?.map(|x| x as usize);
+        Ok(Self { k, metric, batch_rows, values: return_values })
+    }
+}
+
+/// Parameters of a single scan over a bucket.
+struct BucketScan<'a> {
+    executor: &'a ScanExecutor,
+    q: &'a Array2<f32>,
+    /// Number of elements of every value and of the attended vectors.
+    value_size: usize,
+    batch_rows: usize,
+    scan_io: ScanIo,
+    prefetch_depth: usize,
+    options: ScanOptions,
+    /// Number of entries with the highest logits kept for every query and head, whose weights are returned or recorded as their usage.
+    contributors: usize,
+    /// Index the scan goes through, resolved against the indexes the bucket has.
+    index: ScanIndex,
+    scoring: Scoring,
+    positions: Positions,
+    /// Tells for every entry whether the scan attends to it, if the scan is filtered or the bucket has deleted entries.
+    mask: Option<Vec<bool>>,
+}
+
+impl BucketScan<'_> {
+    /// Attention of the queries that has not seen any entry yet.
+    fn attention(&self) -> PartialAttention {
+        PartialAttention::new(self.q.nrows(), self.scoring.heads, self.value_size).with_contributors(self.contributors)
+    }
+
+    /// Logits of the queries against keys `k` of entries `rows`, taking positions of the entries into account.
+    fn logits(&self, k: ArrayView2<f32>, rows: impl Iterator<Item = usize> + Clone) -> Array2<f32> {
+        let rotated = self.positions.rotate(k, rows.clone(), self.scoring.heads);
+        let mut logits = match &rotated {
+            Some(rotated) => self.scoring.logits(self.q.view(), rotated.view()),
+            None => self.scoring.logits(self.q.view(), k),
+        };
+        self.bias(&mut logits, rows);
+        logits
+    }
+
+    /// Adds position bias to `logits` of entries `rows` and masks entries the scan skips.
+    fn bias(&self, logits: &mut Array2<f32>, rows: impl Iterator<Item = usize> + Clone) {
+        self.positions.bias(logits, rows.clone());
+        self.mask(logits, rows);
+    }
+
+    /// Sets logits or scores of entries `rows` the scan skips to negative infinity.
+    fn mask(&self, logits: &mut Array2<f32>, rows: impl Iterator<Item = usize>) {
+        if let Some(mask) = &self.mask {
+            for (mut column, row) in logits.columns_mut().into_iter().zip(rows) {
+                if !mask[row] {
+                    column.fill(f32::NEG_INFINITY);
+                }
+            }
+        }
+    }
+
+    /// Attends over `bucket`, whose keys and values are stored as `T`.
+    async fn run<T: StoredElement>(&self, bucket: &mut Bucket) -> Result<PartialAttention, StorageError> {
+        let q = self.q;
+        match (self.index, self.options.top_k) {
+            (ScanIndex::Hnsw, Some(top_k)) => return self.run_hnsw::<T>(bucket, top_k).await,
+            (ScanIndex::Ivf, None) => {
+                let mut state = self.attention();
+                self.run_ivf::<T>(bucket, |logits, v, rows| state.attend_logits(logits, v, rows.iter().copied())).await?;
+                return Ok(state);
+            }
+            (ScanIndex::Ivf, Some(top_k)) => {
+                let mut state = TopKAttention::new(self.attention(), top_k);
+                self.run_ivf::<T>(bucket, |logits, v, rows| state.attend_logits(logits, v, rows.iter().copied())).await?;
+                return Ok(state.finish());
+            }
+            (_, Some(top_k)) => {
+                let mut state = TopKAttention::new(self.attention(), top_k);
+                self.reduce(bucket, &mut state, |state: &mut TopKAttention, row: usize, k: ArrayView2<T>, v: ArrayView2<T>| {
+                    state.attend_logits(self.logits(T::widen(k).view(), row..row + k.nrows()), T::widen(v).view(), row..row + k.nrows())
+                }).await?;
+                return Ok(state.finish());
+            }
+            _ => {}
+        }
+        match self.options.source {
+            KeySource::Exact => {
+                let mut state = self.attention();
+                self.reduce(bucket, &mut state, |state: &mut PartialAttention, row: usize, k: ArrayView2<T>, v: ArrayView2<T>| {
+                    state.attend_logits(self.logits(T::widen(k).view(), row..row + k.nrows()), T::widen(v).view(), row..row + k.nrows())
+                }).await?;
+                Ok(state)
+            }
+            KeySource::Int8 => self.run_approximate::<T, _>(bucket, |k, row| self.logits(dequantize_int8(k).view(), row..row + k.nrows())).await,
+            KeySource::Pq => {
+                // Lookup tables hold dot products, so the score has to be proportional to them.
+                let scale = self.scoring.dot_scale(q.ncols()).expect("Score of pq scans is checked by the caller");
+                let tables = bucket.product_quantizer().ok_or(InvalidLayoutError)?.lookup_tables(q) * scale;
+                self.run_approximate::<T, _>(bucket, |k, row| {
+                    let mut logits = ProductQuantizer::logits(&tables, k);
+                    self.bias(&mut logits, row..row + k.nrows());
+                    logits
+                }).await
+            }
+        }
+    }
+
+    /// Attends only over entries the HNSW graph finds among the `top_k` highest logits of every query.
+    /// Entries filtered out or deleted are dropped from the found ones, so fewer than `top_k` may remain.
+    async fn run_hnsw<T: StoredElement>(&self, bucket: &mut Bucket, top_k: usize) -> Result<PartialAttention, StorageError> {
+        let q = self.q;
+        let ef = self.options.ef_search.unwrap_or(DEFAULT_EF_SEARCH);
+        let found = bucket.search_hnsw::<T>(q, top_k, ef)?.ok_or(InvalidLayoutError)?;
+        let mut state = self.attention();
+        for (query, rows) in found.iter().enumerate() {
+            let keys = bucket.read_keys::<T>(rows).await?;
+            let values = bucket.read_values::<T>(rows).await?;
+            // Entries found for this query must not contribute to the others.
+            let mut logits = Array2::from_elem((q.nrows(), rows.len()), f32::NEG_INFINITY);
+            logits.row_mut(query).assign(&self.scoring.logits(q.slice(s![query..query + 1, ..]), keys.view()).row(0));
+            self.bias(&mut logits, rows.iter().copied());
+            state.attend_logits(logits, values.view(), rows.iter().copied());
+        }
+        Ok(state)
     }
 
-    /// Store the entry in the bucket `bucket_name`
-    pub fn store(&mut self, k: Vec<f32>, v: Vec<f32>, bucket_name: &'_ str) {
-        todo!()
+    /// Finds `options.k` entries of the bucket with the best scores for every query.
+    async fn search<T: StoredElement>(&self, bucket: &mut Bucket, options: SearchOptions) -> Result<Vec<Vec<Neighbour>>, StorageError> {
+        let q = self.q;
+        let mut state = NearestNeighbours::new(q.nrows(), options.k);
+        self.reduce(bucket, &mut state, |state: &mut NearestNeighbours, row: usize, k: ArrayView2<T>, v: ArrayView2<T>| {
+            let values = options.values.then(|| T::widen(v));
+            let mut scores = options.metric.scores(q, T::widen(k).view());
+            self.mask(&mut scores, row..row + k.nrows());
+            state.offer(scores, values.as_ref().map(|v| v.view()), row..row + k.nrows())
+        }).await?;
+        Ok(state.finish())
     }
 
-    pub async fn load_from_path(path: impl Into<PathBuf>) -> Result<Self, InitializationError> {
-        let path = path.into();
-        let info_file = path.join("bc_info.index");
-        let content = tokio::fs::read_to_string(info_file).await?;
-        let mut buckets = vec![];
-        for bucket in content.split('\n') {
-            if bucket == "" {
+    /// Passes logits and values of entries of the `nprobe` inverted file lists whose centroids are closest to every query
+    /// to `attend`, along with the rows of the entries. Logits of entries of lists a query did not probe are negative infinity.
+    /// Every probed list is read once, in batches of `batch_rows` entries, for all queries probing it.
+    async fn run_ivf<T: StoredElement>(&self, bucket: &mut Bucket, mut attend: impl FnMut(Array2<f32>, ArrayView2<f32>, &[usize])) -> Result<(), StorageError> {
+        let q = self.q;
+        let nprobe = self.options.nprobe.unwrap_or(DEFAULT_NPROBE);
+        let index = bucket.ivf().ok_or(InvalidLayoutError)?;
+        let mut probed = vec![vec![false; q.nrows()]; index.configuration().lists];
+        for (query, row) in q.rows().into_iter().enumerate() {
+            for list in index.probe(row, nprobe) {
+                probed[list][query] = true;
+            }
+        }
+        for (list, queries) in probed.iter().enumerate() {
+            if !queries.contains(&true) {
                 continue;
-            };
-            let bucket_path = path.join(format!("{}.kvs", &bucket));
-            if bucket_path.exists().not() || bucket_path.is_file().not() {
-                return Err(InitializationError::new_inconsistency_error(
-                    bucket_path.display().to_string(),
-                    "file",
-                    if bucket_path.exists() {
-                        "directory"
-                    } else {
-                        "nto exists"
-                    },
-                ));
-            };
-            buckets.push(bucket.to_string())
+            }
+            let rows: Vec<usize> = index.list(list).iter().map(|&row| row as usize).collect();
+            for batch in rows.chunks(self.batch_rows) {
+                let keys = bucket.read_keys::<T>(batch).await?;
+                let values = bucket.read_values::<T>(batch).await?;
+                let mut logits = self.logits(keys.view(), batch.iter().copied());
+                // Entries of this list must not contribute to queries that did not probe it.
+                for (row, mut logits) in logits.rows_mut().into_iter().enumerate() {
+                    if !queries[row / self.scoring.heads] {
+                        logits.fill(f32::NEG_INFINITY);
+                    }
+                }
+                attend(logits, values.view(), batch);
+            }
         }
-        Ok(Self {
-            buckets,
-            data_directory: path,
-            vec_size: 32,
-        })
+        Ok(())
     }
 
-    pub async fn init_database(
-        path: impl Into<PathBuf>,
-        vec_size: u32,
-    ) -> Result<Self, InitializationError> {
-        let path = path.into();
-        tokio::fs::create_dir_all(&path).await?;
-        tokio::fs::write(path.join("bc_info.index"), "").await?;
-        Ok(Self {
-            vec_size,
-            buckets: vec![],
-            data_directory: path,
-        })
+    /// Attends over logits computed by `logits` from approximate keys of a batch and index of its first entry.
+    /// If rescoring is requested, logits of the best entries are then recomputed from exact keys.
+    async fn run_approximate<T: StoredElement, L>(&self, bucket: &mut Bucket, logits: L) -> Result<PartialAttention, StorageError>
+    where
+        L: Fn(ArrayView2<u8>, usize) -> Array2<f32> + Sync,
+    {
+        if self.options.rescore == 0 {
+            let mut state = self.attention();
+            self.reduce(bucket, &mut state, |state: &mut PartialAttention, row: usize, k: ArrayView2<u8>, v: ArrayView2<T>| {
+                state.attend_logits(logits(k, row), T::widen(v).view(), row..row + v.nrows())
+            }).await?;
+            return Ok(state);
+        }
+        let mut state = RescoringAttention::new(self.attention(), self.options.rescore);
+        self.reduce(bucket, &mut state, |state: &mut RescoringAttention, row: usize, k: ArrayView2<u8>, v: ArrayView2<T>| {
+            state.attend_logits(logits(k, row), T::widen(v).view(), row)
+        }).await?;
+        let rows = state.rows();
+        let keys = bucket.read_keys::<T>(&rows).await?;
+        let logits = self.logits(keys.view(), rows.iter().copied());
+        Ok(state.rescore(|query, row| {
+            let key = rows.binary_search(&row).expect("Keys of every held back entry are read above");
+            logits[[query, key]]
+        }))
+    }
+
+    /// Calls `f` on every batch of the bucket on the scan workers, merging the results into `state`.
+    async fn reduce<K: Sync, V: Sync, P: Partial, F>(&self, bucket: &mut Bucket, state: &mut P, f: F) -> Result<(), StorageError>
+    where
+        F: Fn(&mut P, usize, ArrayView2<K>, ArrayView2<V>) + Sync,
+    {
+        let kernel = |state: &mut P, row: usize, k: ArrayView2<K>, v: ArrayView2<V>| self.executor.reduce(state, row, k, v, self.batch_rows, &f);
+        let chunk_size = self.executor.chunk_size(self.batch_rows);
+        match self.scan_io {
+            ScanIo::Mmap => bucket.reduce_kv_mapped(self.options.source, state, chunk_size, self.prefetch_depth, kernel).await,
+            ScanIo::Read => bucket.reduce_kv_batched(self.options.source, state, chunk_size, self.prefetch_depth, kernel).await,
+        }
     }
 }
 
-#[derive(Error, Debug)]
-pub enum InitializationError {
-    #[error("Expected `{0}` to exist and be a `{1}`, but instead received `{2}`. That means that database is corrupted. You can try to fix yourself, but you probably should restore the backup.")]
-    InconsistentDataDirectory(String, String, String),
-    #[error("{0}")]
-    IOError(std::io::Error),
+/// Returns value of string property `name`.
+fn get_string_property<'a>(properties: &'a PropertyList, name: &'static str) -> Result<Option<&'a str>, ExecutionError> {
+    match properties.iter().find(|x| x.name == name).map(|x| &x.data) {
+        None => Ok(None),
+        Some(PropertyValue::String(v)) => Ok(Some(v.as_str())),
+        Some(PropertyValue::Integer(_)) => Err(ExecutionError::TypeMismatch { expected: "String", found: "Integer", property: name }),
+        Some(PropertyValue::Float(_)) => Err(ExecutionError::TypeMismatch { expected: "String", found: "Float", property: name }),
+    }
 }
 
-impl From<std::io::Error> for InitializationError {
-    fn from(value: Error) -> Self {
-        return Self::IOError(value);
+/// Returns value of `dtype` property.
+fn get_dtype_property(properties: &PropertyList) -> Result<Option<DType>, ExecutionError> {
+    match get_string_property(properties, "dtype")? {
+        None => Ok(None),
+        Some(name) => DType::parse(name).map(Some).ok_or_else(|| ExecutionError::InvalidPropertyValue {
+            property: "dtype",
+            value: name.to_string(),
+            expected: "one of f32, f16, bf16",
+        }),
     }
 }
 
-impl InitializationError {
-    pub fn new_inconsistency_error(
-        inconsistent_object_name: impl Into<String>,
-        required_state: impl Into<String>,
-        found_state: impl Into<String>,
-    ) -> Self {
-        InitializationError::InconsistentDataDirectory(
-            inconsistent_object_name.into(),
-            required_state.into(),
-            found_state.into(),
-        )
+/// Returns value of `key_quantization` property.
+fn get_key_quantization_property(properties: &PropertyList) -> Result<Option<KeyQuantization>, ExecutionError> {
+    match get_string_property(properties, "key_quantization")? {
+        None => Ok(None),
+        Some(name) => KeyQuantization::parse(name).map(Some).ok_or_else(|| ExecutionError::InvalidPropertyValue {
+            property: "key_quantization",
+            value: name.to_string(),
+            expected: "one of none, int8",
+        }),
+    }
+}
+
+/// Returns value of `eviction` property.
+fn get_eviction_property(properties: &PropertyList) -> Result<Option<Eviction>, ExecutionError> {
+    match get_string_property(properties, "eviction")? {
+        None => Ok(None),
+        Some(name) => Eviction::parse(name).map(Some).ok_or_else(|| ExecutionError::InvalidPropertyValue {
+            property: "eviction",
+            value: name.to_string(),
+            expected: "one of fifo, lru, lfu",
+        }),
     }
 }
 
+/// Returns configuration of the HNSW graph if `index = hnsw` is given, taking parameters from `hnsw_m` and `ef_construction`.
+fn get_hnsw_property(properties: &PropertyList) -> Result<Option<HnswConfiguration>, ExecutionError> {
+    match get_string_property(properties, "index")? {
+        None => Ok(None),
+        Some(name) if name.eq_ignore_ascii_case("hnsw") => {
+            let default = HnswConfiguration::default();
+            let m = get_positive_integer_property(properties, "hnsw_m")?.map_or(default.m, |x| x as usize);
+            if m < 2 {
+                return Err(ExecutionError::InvalidPropertyValue { property: "hnsw_m", value: m.to_string(), expected: "at least 2" });
+            }
+            let ef_construction = get_positive_integer_property(properties, "ef_construction")?.map_or(default.ef_construction, |x| x as usize);
+            Ok(Some(HnswConfiguration { m, ef_construction }))
+        }
+        Some(name) => Err(ExecutionError::InvalidPropertyValue { property: "index", value: name.to_string(), expected: "hnsw" }),
+    }
+}
+
+/// Index built by `BUILD INDEX`.
+enum IndexKind {
+    Hnsw(HnswConfiguration),
+    Ivf,
+}
+
+/// Returns kind of the index to build given by `index` property, inverted file index by default.
+fn get_index_kind_property(properties: &PropertyList) -> Result<IndexKind, ExecutionError> {
+    match get_string_property(properties, "index")? {
+        None => Ok(IndexKind::Ivf),
+        Some(name) if name.eq_ignore_ascii_case("ivf") => Ok(IndexKind::Ivf),
+        Some(name) if name.eq_ignore_ascii_case("hnsw") => Ok(IndexKind::Hnsw(get_hnsw_property(properties)?.expect("Property `index` is hnsw"))),
+        Some(name) => Err(ExecutionError::InvalidPropertyValue { property: "index", value: name.to_string(), expected: "one of ivf, hnsw" }),
+    }
+}
+
+/// Returns position mode given by `position` property, taking its parameter from `alibi_slope`, `rope_base` or `decay_half_life`.
+fn get_position_property(properties: &PropertyList) -> Result<Option<Position>, ExecutionError> {
+    let slope = get_positive_float_property(properties, "alibi_slope")?;
+    let base = get_positive_float_property(properties, "rope_base")?;
+    let half_life = get_positive_float_property(properties, "decay_half_life")?;
+    let position = match get_string_property(properties, "position")? {
+        None => None,
+        Some(name) => Some(match name.to_lowercase().as_str() {
+            "none" => Position::None,
+            "alibi" => Position::Alibi { slope: slope.unwrap_or(DEFAULT_ALIBI_SLOPE) },
+            "rope" => Position::Rope { base: base.unwrap_or(DEFAULT_ROPE_BASE) },
+            "decay" => Position::Decay { half_life: half_life.unwrap_or(DEFAULT_DECAY_HALF_LIFE) },
+            _ => return Err(ExecutionError::InvalidPropertyValue { property: "position", value: name.to_string(), expected: "one of none, alibi, rope, decay" }),
+        }),
+    };
+    if slope.is_some() && !matches!(position, Some(Position::Alibi { .. })) {
+        return Err(ExecutionError::PropertyNotApplicable { property: "alibi_slope", context: "with position = alibi" });
+    }
+    if base.is_some() && !matches!(position, Some(Position::Rope { .. })) {
+        return Err(ExecutionError::PropertyNotApplicable { property: "rope_base", context: "with position = rope" });
+    }
+    if half_life.is_some() && !matches!(position, Some(Position::Decay { .. })) {
+        return Err(ExecutionError::PropertyNotApplicable { property: "decay_half_life", context: "with position = decay" });
+    }
+    Ok(position)
+}
+
+/// Returns value of `score` property.
+fn get_score_property(properties: &PropertyList) -> Result<Option<Score>, ExecutionError> {
+    match get_string_property(properties, "score")? {
+        None => Ok(None),
+        Some(name) => Score::parse(name).map(Some).ok_or_else(|| ExecutionError::InvalidPropertyValue {
+            property: "score",
+            value: name.to_string(),
+            expected: "one of dot, scaled_dot, cosine, negative_l2",
+        }),
+    }
+}
+
+/// Returns value of `heads` property, making sure keys of `key_size` and values of `value_size` elements split into that many heads.
+fn get_heads_property(properties: &PropertyList, key_size: usize, value_size: usize) -> Result<Option<usize>, ExecutionError> {
+    get_positive_integer_property(properties, "heads")?.map(|heads| check_heads(heads as usize, key_size, value_size)).transpose()
+}
+
+/// Makes sure keys of `key_size` and values of `value_size` elements split into `heads` heads of equal size.
+fn check_heads(heads: usize, key_size: usize, value_size: usize) -> Result<usize, ExecutionError> {
+    if !key_size.is_multiple_of(heads) || !value_size.is_multiple_of(heads) {
+        return Err(ExecutionError::InvalidPropertyValue { property: "heads", value: heads.to_string(), expected: "a divisor of key_size and value_size" });
+    }
+    Ok(heads)
+}
+
+/// Makes sure projection matrices have the version given by `projection_version` property, if it is given.
+fn check_projection_version(properties: &PropertyList, current: u32) -> Result<(), ExecutionError> {
+    match get_positive_integer_property(properties, "projection_version")? {
+        Some(version) if version as u32 != current => Err(ExecutionError::ProjectionVersionMismatch { expected: version as u32, current }),
+        _ => Ok(()),
+    }
+}
+
+/// Multiplies every vector by `matrix`, if one is given. Vectors must have the input size of the matrix.
+fn project(matrix: Option<&Matrix>, vectors: Vec<Vec<f32>>) -> Vec<Vec<f32>> {
+    let Some(matrix) = matrix else {
+        return vectors;
+    };
+    let x = Array2::from_shape_vec((vectors.len(), matrix.inputs()), vectors.into_iter().flatten().collect()).expect("Sizes are checked by the caller");
+    matrix.apply(x.view()).rows().into_iter().map(|r| r.to_vec()).collect()
+}
+
+/// Returns value of number property `name`, making sure it is positive.
+fn get_positive_float_property(properties: &PropertyList, name: &'static str) -> Result<Option<f32>, ExecutionError> {
+    let value = match properties.iter().find(|x| x.name == name).map(|x| &x.data) {
+        None => { return Ok(None); }
+        Some(PropertyValue::Integer(v)) => *v as f32,
+        Some(PropertyValue::Float(v)) => *v,
+        Some(PropertyValue::String(_)) => { return Err(ExecutionError::TypeMismatch { expected: "Float", found: "String", property: name }); }
+    };
+    if !(value > 0. && value.is_finite()) {
+        return Err(ExecutionError::InvalidPropertyValue { property: name, value: value.to_string(), expected: "a positive number" });
+    }
+    Ok(Some(value))
+}
+
+/// Returns value of integer property `name`, making sure it is positive.
+fn get_positive_integer_property(properties: &PropertyList, name: &'static str) -> Result<Option<i32>, ExecutionError> {
+    let value = match properties.iter().find(|x| x.name == name).map(|x| &x.data) {
+        None => { return Ok(None); }
+        Some(PropertyValue::Integer(v)) => *v,
+        Some(PropertyValue::Float(_)) => { return Err(ExecutionError::TypeMismatch { expected: "Unsigned integer", found: "Float", property: name }); }
+        Some(PropertyValue::String(_)) => { return Err(ExecutionError::TypeMismatch { expected: "Unsigned integer", found: "String", property: name }); }
+    };
+    if value <= 0 {
+        return Err(ExecutionError::TypeMismatch {
+            expected: "Unsigned integer",
+            found: "Signed integer",
+            property: name,
+        });
+    }
+    Ok(Some(value))
+}
+
+/// Returns the number of entries every scan worker attends at once and the bytes of memory the scan needs.
+/// Unless `requested` is given, batch size is the largest one that fits into `budget`.
+fn plan_batches(shape: &ScanShape, rows: usize, requested: Option<usize>, budget: usize) -> (usize, usize) {
+    // There is no point in batches larger than the part of the bucket each worker gets.
+    let rows_per_worker = rows.div_ceil(shape.threads).max(1);
+    let batch_rows = requested.unwrap_or_else(|| shape.batch_rows_for_budget(budget)).min(rows_per_worker);
+    (batch_rows, shape.memory(batch_rows))
+}
+
+#[derive(Parser, Debug)]
+pub struct Args {
+    #[arg(
+    long,
+    default_value = "./qkv-config.json",
+    long_help = "Path to database configuration file."
+    )]
+    pub config: PathBuf,
+    #[arg(long, default_value = None, long_help = "Path to command list that will be executed during initialization.")]
+    pub init: Option<PathBuf>,
+}
+
+/// How bucket files are read during scans.
+#[derive(Serialize, Deserialize, Debug, Default, Copy, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ScanIo {
+    /// Map bucket files into memory and attend over them without copying.
+    #[default]
+    Mmap,
+    /// Read bucket files batch by batch into intermediate buffers.
+    Read,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Configuration {
+    data_directory: PathBuf,
+    #[serde(default)]
+    scan_io: ScanIo,
+    /// Number of worker threads used by a single scan. Defaults to the number of CPUs.
+    #[serde(default)]
+    scan_threads: Option<usize>,
+    /// Number of batches read ahead of the one being attended. Defaults to [`DEFAULT_PREFETCH_DEPTH`].
+    #[serde(default)]
+    scan_prefetch_depth: Option<usize>,
+    /// Bytes of memory a scan may use. Batch sizes are derived from it and scans that need more are refused.
+    /// Commands run one at a time, so scans never share it. Defaults to [`DEFAULT_SCAN_MEMORY_BUDGET`].
+    #[serde(default)]
+    scan_memory_budget: Option<usize>,
+    /// Seconds between sweeps deleting expired entries, writing usage and compacting buckets, see [`Engine::sweep`]. Defaults to [`DEFAULT_EXPIRATION_INTERVAL`].
+    #[serde(default)]
+    expiration_interval: Option<f32>,
+}
+
+#[derive(Debug, Error)]
+pub enum InitializationError {
+    #[error("{0}")]
+    StorageError(#[from] StorageError),
+    #[error("Unable to start scan workers: {0}")]
+    ThreadPoolError(#[from] ThreadPoolBuildError),
+}
+
+const DEFAULT_SCAN_MEMORY_BUDGET: usize = 1 << 30;
+
+const DEFAULT_PQ_ITERATIONS: usize = 25;
+
+const DEFAULT_EF_SEARCH: usize = 64;
+
+const DEFAULT_NPROBE: usize = 8;
+
+const DEFAULT_SEARCH_K: usize = 10;
+
+/// Number of entries returned by `RETURN WEIGHTS` for every query by default.
+const DEFAULT_WEIGHTS_LIMIT: usize = 10;
+
+/// Number of entries with the highest logits whose attention weight is recorded as their usage for every query and head,
+/// in scans of buckets evicting by usage. The weight the other entries receive is not credited to them,
+/// so usage approximates the attention entries received, see `Layout.md`.
+const USAGE_CONTRIBUTORS: usize = 16;
+
+/// Number of keys product quantizer codebooks are trained on by default.
+const DEFAULT_PQ_SAMPLE: usize = 16384;
+
+const DEFAULT_IVF_ITERATIONS: usize = 25;
+
+/// Number of keys inverted file centroids are trained on by default.
+const DEFAULT_IVF_SAMPLE: usize = 65536;
+
+const DEFAULT_ALIBI_SLOPE: f32 = 0.0625;
+
+const DEFAULT_ROPE_BASE: f32 = 10000.;
+
+/// One day, in seconds.
+const DEFAULT_DECAY_HALF_LIFE: f32 = 86400.;
+
+/// Double buffering: one batch is attended while the next one is being read.
+const DEFAULT_PREFETCH_DEPTH: usize = 1;
+
+const DEFAULT_EXPIRATION_INTERVAL: f32 = 60.;
+
 pub struct Engine {
     storage: Storage,
+    scan_io: ScanIo,
+    prefetch_depth: usize,
+    executor: ScanExecutor,
+    /// Bytes of memory a scan may use, see [`Configuration::scan_memory_budget`].
+    scan_memory_budget: usize,
 }
 
 impl Engine {
-    pub async fn new(conf: Configuration) -> Self {
-        Self {
-            storage: Storage::from_disk(conf.data_directory).await.unwrap()
-        }
+    pub async fn new(conf: Configuration) -> Result<Self, InitializationError> {
+        let threads = conf.scan_threads.unwrap_or_else(num_cpus::get);
+        Ok(Self {
+            storage: Storage::from_disk(conf.data_directory).await?,
+            scan_io: conf.scan_io,
+            prefetch_depth: conf.scan_prefetch_depth.unwrap_or(DEFAULT_PREFETCH_DEPTH),
+            executor: ScanExecutor::new(threads)?,
+            scan_memory_budget: conf.scan_memory_budget.unwrap_or(DEFAULT_SCAN_MEMORY_BUDGET),
+        })
     }
 
     pub async fn create_database(
         &mut self,
         name: String,
-        vec_size: u32,
+        configuration: DatabaseConfiguration,
     ) -> Result<(), ExecutionError> {
-        self.storage.create_database(&name, DatabaseConfiguration {
-            qkv_vec_size: vec_size
-        }).await.unwrap();
+        self.storage.create_database(&name, configuration).await?;
         Ok(())
     }
 
-    pub async fn execute(&mut self, command: Command) -> Result<Option<Vec<Vec<f32>>>, ExecutionError> {
+    pub async fn execute(&mut self, command: Command) -> Result<Option<Output>, ExecutionError> {
         match command {
             Command::CreateDatabase { name, properties } => {
-                if self.storage.get_database(&name).await.unwrap().is_some() {
+                if self.storage.get_database(&name).await.is_some() {
                     return Err(ExecutionError::EntityAlreadyExists { name, ty: EntityType::Database });
                 }
 
-                let prop = properties.iter().find(|x| x.name == "qkv_vec_size").map(|x| x.clone().data);
-                let qkv_vec_size = match prop {
-                    Some(v) => match v {
-                        PropertyValue::Integer(v) => Ok(v),
-                        PropertyValue::Float(_) => Err(ExecutionError::TypeMismatch { expected: "Unsigned integer", found: "Float", property: "qkv_vec_size" }),
-                        PropertyValue::String(_) => Err(ExecutionError::TypeMismatch { expected: "Unsigned integer", found: "String", property: "qkv_vec_size" })
-                    }
-                    None => Ok(512)
-                }?;
-                if qkv_vec_size <= 0 {
-                    return Err(ExecutionError::TypeMismatch {
-                        expected: "Unsigned integer",
-                        found: "Signed integer",
-                        property: "qkv_vec_size",
-                    });
-                }
-                self.create_database(name, qkv_vec_size as u32).await?;
+                // `qkv_vec_size` is the name of the key size from before values could have their own size.
+                let key_size = match get_positive_integer_property(&properties, "key_size")? {
+                    Some(size) => size,
+                    None => get_positive_integer_property(&properties, "qkv_vec_size")?.unwrap_or(512),
+                };
+                let value_size = get_positive_integer_property(&properties, "value_size")?.unwrap_or(key_size);
+                let heads = get_heads_property(&properties, key_size as usize, value_size as usize)?.unwrap_or(1);
+                let dtype = get_dtype_property(&properties)?.unwrap_or_default();
+                self.create_database(name, DatabaseConfiguration {
+                    key_size: key_size as u32,
+                    value_size: Some(value_size as u32),
+                    dtype,
+                    score: get_score_property(&properties)?.unwrap_or_default(),
+                    temperature: get_positive_float_property(&properties, "temperature")?.unwrap_or(1.),
+                    heads,
+                    projection_version: 0,
+                }).await?;
                 Ok(None)
             }
             Command::CreateBucket { database, name, properties } => {
-                self.create_bucket(&name, &database).await?;
+                self.create_bucket(&name, &database, &properties).await?;
                 Ok(None)
             }
-            Command::Insert { database, bucket, entries, properties } => {
-                // Checking that all vectors have same and valid size
-                let target_size = match self.storage.get_database(&database).await.unwrap() {
-                    None => { return Err(ExecutionError::DatabaseDoesNotExist { database: database.into() }); }
-                    Some(x) => { x.get_qkv_vec_size() }
-                };
+            Command::Insert { database, bucket, entries, metadata, ids, properties } => {
+                let db = self.storage.get_database(&database).await.ok_or_else(|| ExecutionError::DatabaseDoesNotExist { database: database.clone() })?;
+                check_projection_version(&properties, db.get_configuration().projection_version)?;
+                let ttl = get_positive_float_property(&properties, "ttl")?;
+                let projection = db.get_projection();
+                let (wk, wv) = (projection.and_then(|p| p.wk.as_ref()), projection.and_then(|p| p.wv.as_ref()));
+                // Checking that all vectors have same and valid size, raw embeddings have the input size of the projection
+                let key_size = wk.map_or(db.get_key_size(), |m| m.inputs() as u32);
+                let value_size = wv.map_or(db.get_value_size(), |m| m.inputs() as u32);
                 for (k, v) in entries.iter() {
-                    if target_size != k.len() as u32 {
+                    if key_size != k.len() as u32 {
                         return Err(ExecutionError::SizeMismatch {
-                            expected: target_size,
+                            vector: "key",
+                            expected: key_size,
                             got: k.len() as u32,
                         });
                     }
 
-                    if target_size != v.len() as u32 {
+                    if value_size != v.len() as u32 {
                         return Err(ExecutionError::SizeMismatch {
-                            expected: target_size,
-                            got: k.len() as u32,
+                            vector: "value",
+                            expected: value_size,
+                            got: v.len() as u32,
                         });
                     }
                 }
+                let entries = if wk.is_some() || wv.is_some() {
+                    let (keys, values): (Vec<Vec<f32>>, Vec<Vec<f32>>) = entries.into_iter().unzip();
+                    project(wk, keys).into_iter().zip(project(wv, values)).collect()
+                } else {
+                    entries
+                };
 
-                self.insert(entries, &bucket, &database).await;
-                Ok(None)
+                match ids {
+                    None => {
+                        self.insert(entries, metadata, ttl, &bucket, &database).await?;
+                        Ok(None)
+                    }
+                    Some(ids) => Ok(Some(Output::Properties(self.upsert(entries, metadata, ids, ttl, &bucket, &database).await?))),
+                }
             }
-            Command::Scan { database, bucket, queries, properties } => {
+            Command::Update { database, bucket, value, filter } => {
+                Ok(Some(Output::Properties(self.update(value, &bucket, &database, &filter).await?)))
+            }
+            Command::Scan { database, bucket, queries, filter, properties, return_weights } => {
                 let bucket = match bucket {
-                    ScanTargetBucket::Hot => { todo!() }
-                    ScanTargetBucket::All => { todo!() }
+                    ScanTargetBucket::Hot => { return Err(ExecutionError::UnsupportedBucket { bucket: "HOT" }); }
+                    ScanTargetBucket::All => { return Err(ExecutionError::UnsupportedBucket { bucket: "ALL" }); }
                     ScanTargetBucket::Physical(name) => { name }
                 };
 
-                let target_size = match self.storage.get_database(&database).await.unwrap() {
-                    None => { return Err(ExecutionError::DatabaseDoesNotExist { database }); }
-                    Some(c) => { c }
-                }.get_qkv_vec_size();
-                for q in queries.iter() {
-                    if target_size != q.len() as u32 {
-                        return Err(ExecutionError::SizeMismatch { expected: target_size, got: q.len() as u32 });
-                    }
-                }
-                Ok(Some(self.scan(queries, &bucket, &database).await?))
+                let queries = self.project_queries(&database, queries, &properties).await?;
+                let options = ScanOptions::from_properties(&properties, return_weights)?;
+                Ok(Some(self.scan(queries, &bucket, &database, options, filter.as_ref()).await?))
+            }
+            Command::Search { database, bucket, queries, properties, return_values } => {
+                let queries = self.project_queries(&database, queries, &properties).await?;
+                let options = SearchOptions::from_properties(&properties, return_values)?;
+                Ok(Some(self.search(queries, &bucket, &database, options).await?))
+            }
+            Command::Describe { database, bucket } => {
+                Ok(Some(Output::Properties(self.describe(&database, bucket.as_deref()).await?)))
+            }
+            Command::Compress { database, bucket, properties } => {
+                self.compress(&bucket, &database, &properties).await?;
+                Ok(None)
+            }
+            Command::BuildIndex { database, bucket, properties } => {
+                self.build_index(&bucket, &database, &properties).await?;
+                Ok(None)
+            }
+            Command::Delete { database, bucket, filter } => {
+                Ok(Some(Output::Properties(self.delete(&bucket, &database, &filter).await?)))
+            }
+            Command::Compact { database, bucket } => {
+                let db = self.storage.get_database(&database).await.ok_or_else(|| ExecutionError::DatabaseDoesNotExist { database: database.clone() })?;
+                let Some(compaction) = db.compact_bucket(&bucket).await? else {
+                    return Err(ExecutionError::BucketDoesNotExist { database, bucket });
+                };
+                Ok(Some(Output::Properties(vec![
+                    ("removed", (compaction.entries - compaction.previous_ids.len()).to_string()),
+                    ("previous_ids", format_list(&compaction.previous_ids)),
+                ])))
+            }
+            Command::Get { database, bucket, entries } => {
+                Ok(Some(self.get(entries, &bucket, &database).await?))
+            }
+            Command::SetProjection { database, wq, wk, wv } => {
+                let version = self.set_projection(&database, wq, wk, wv).await?;
+                Ok(Some(Output::Properties(vec![("projection_version", version.to_string())])))
             }
             Command::Dummy => {
                 Ok(None)
             }
         }
     }
-    async fn create_bucket(&mut self, bucket_name: &str, database: &str) -> Result<(), ExecutionError> {
-        match self.storage.get_database(database).await.unwrap() {
+    /// Makes sure the database exists and projection matrices have the version given by `projection_version` property,
+    /// then projects raw query embeddings with `wq` matrix of the database if it has one.
+    async fn project_queries(&mut self, database: &str, queries: Vec<Vec<f32>>, properties: &PropertyList) -> Result<Vec<Vec<f32>>, ExecutionError> {
+        let db = self.storage.get_database(database).await.ok_or_else(|| ExecutionError::DatabaseDoesNotExist { database: database.into() })?;
+        check_projection_version(properties, db.get_configuration().projection_version)?;
+        let Some(wq) = db.get_projection().and_then(|p| p.wq.as_ref()) else {
+            self.check_queries(database, &queries).await?;
+            return Ok(queries);
+        };
+        for q in queries.iter() {
+            if wq.inputs() != q.len() {
+                return Err(ExecutionError::SizeMismatch { vector: "query", expected: wq.inputs() as u32, got: q.len() as u32 });
+            }
+        }
+        Ok(project(Some(wq), queries))
+    }
+
+    /// Replaces projection matrices of the database after making sure they produce vectors of its key and value sizes.
+    /// Returns version of the new matrices.
+    async fn set_projection(&mut self, database: &str, wq: Option<Vec<Vec<f32>>>, wk: Option<Vec<Vec<f32>>>, wv: Option<Vec<Vec<f32>>>) -> Result<u32, ExecutionError> {
+        let db = self.storage.get_database(database).await.ok_or_else(|| ExecutionError::DatabaseDoesNotExist { database: database.into() })?;
+        let (key_size, value_size) = (db.get_key_size() as usize, db.get_value_size() as usize);
+        let matrix = |name: &'static str, rows: Option<Vec<Vec<f32>>>, outputs: usize| -> Result<Option<Matrix>, ExecutionError> {
+            let Some(rows) = rows else {
+                return Ok(None);
+            };
+            let matrix = Matrix::from_rows(rows).ok_or(ExecutionError::InvalidMatrix { matrix: name })?;
+            if matrix.outputs() != outputs {
+                return Err(ExecutionError::SizeMismatch { vector: name, expected: outputs as u32, got: matrix.outputs() as u32 });
+            }
+            Ok(Some(matrix))
+        };
+        let projection = Projection { wq: matrix("wq", wq, key_size)?, wk: matrix("wk", wk, key_size)?, wv: matrix("wv", wv, value_size)? };
+        Ok(db.set_projection(projection).await?)
+    }
+
+    /// Deletes entries of the bucket matching `filter`. Returns the number of deleted entries and whether deleted entries
+    /// reach the compaction threshold of the bucket, in which case the next sweep compacts it, see [`Engine::sweep`].
+    async fn delete(&mut self, bucket_name: &str, database: &str, filter: &Filter) -> Result<Vec<(&'static str, String)>, ExecutionError> {
+        let db = self.storage.get_database(database).await.ok_or_else(|| ExecutionError::DatabaseDoesNotExist { database: database.into() })?;
+        let bucket = db.get_bucket(bucket_name).await.ok_or_else(|| ExecutionError::BucketDoesNotExist { database: database.into(), bucket: bucket_name.into() })?;
+        let rows: Vec<usize> = bucket.filter(filter).await?.iter().enumerate().filter(|(_, &matches)| matches).map(|(row, _)| row).collect();
+        let deleted = bucket.delete(&rows).await?;
+        Ok(vec![("deleted", deleted.to_string()), ("needs_compaction", bucket.needs_compaction().to_string())])
+    }
+
+    /// Overwrites values of entries of the bucket matching `filter`, projecting the raw value with `wv` matrix of the database
+    /// if it has one. Returns the number of updated entries.
+    async fn update(&mut self, value: Vec<f32>, bucket_name: &str, database: &str, filter: &Filter) -> Result<Vec<(&'static str, String)>, ExecutionError> {
+        let db = self.storage.get_database(database).await.ok_or_else(|| ExecutionError::DatabaseDoesNotExist { database: database.into() })?;
+        let wv = db.get_projection().and_then(|p| p.wv.as_ref());
+        let value_size = wv.map_or(db.get_value_size(), |m| m.inputs() as u32);
+        if value_size != value.len() as u32 {
+            return Err(ExecutionError::SizeMismatch { vector: "value", expected: value_size, got: value.len() as u32 });
+        }
+        let value = project(wv, vec![value]).remove(0);
+        let bucket = db.get_bucket(bucket_name).await.ok_or_else(|| ExecutionError::BucketDoesNotExist { database: database.into(), bucket: bucket_name.into() })?;
+        let rows: Vec<usize> = bucket.filter(filter).await?.iter().enumerate().filter(|(_, &matches)| matches).map(|(row, _)| row).collect();
+        bucket.update_values(&rows, value).await?;
+        Ok(vec![("updated", rows.len().to_string())])
+    }
+
+    /// Reads keys and values of the selected entries. Listed entries must exist and be neither deleted nor expired,
+    /// such entries of a range are left out and the range ends at the last entry of the bucket.
+    async fn get(&mut self, entries: EntrySelection, bucket_name: &str, database: &str) -> Result<Output, ExecutionError> {
+        let db = self.storage.get_database(database).await.ok_or_else(|| ExecutionError::DatabaseDoesNotExist { database: database.into() })?;
+        let bucket = db.get_bucket(bucket_name).await.ok_or_else(|| ExecutionError::BucketDoesNotExist { database: database.into(), bucket: bucket_name.into() })?;
+        let rows = bucket.rows().await.map_err(StorageError::from)?;
+        let ids: Vec<usize> = match entries {
+            EntrySelection::Ids(ids) => {
+                if let Some(&id) = ids.iter().find(|&&id| id >= rows || bucket.is_deleted(id) || bucket.is_expired(id)) {
+                    return Err(ExecutionError::EntryDoesNotExist { bucket: bucket_name.into(), id });
+                }
+                ids
+            }
+            EntrySelection::Range { start, end } => (start..end.min(rows)).filter(|&id| !bucket.is_deleted(id) && !bucket.is_expired(id)).collect(),
+        };
+        let (keys, values) = bucket.read_entries(&ids).await?;
+        let metadata = if bucket.has_metadata() { Some(bucket.read_metadata(&ids).await?) } else { None };
+        Ok(Output::Entries {
+            ids,
+            keys: keys.outer_iter().map(|row| row.to_vec()).collect(),
+            values: values.outer_iter().map(|row| row.to_vec()).collect(),
+            metadata,
+        })
+    }
+
+    /// Deletes expired entries of all buckets, writes usage recorded by scans since the last sweep
+    /// and compacts buckets whose deleted entries reach their compaction threshold.
+    /// Returns the database, name and renumbering of every compacted bucket.
+    async fn sweep(&mut self) -> Result<Vec<(String, String, Compaction)>, ExecutionError> {
+        let mut compacted = vec![];
+        for (database, db) in self.storage.databases_mut() {
+            let names: Vec<String> = db.bucket_names().into_iter().map(String::from).collect();
+            for name in names {
+                let bucket = db.get_bucket(&name).await.expect("Bucket names are taken from the database");
+                bucket.expire().await?;
+                bucket.flush_usage().await.map_err(StorageError::from)?;
+                if bucket.needs_compaction() {
+                    let compaction = db.compact_bucket(&name).await?.expect("Bucket names are taken from the database");
+                    compacted.push((database.to_string(), name, compaction));
+                }
+            }
+        }
+        Ok(compacted)
+    }
+
+    /// Makes sure the database exists and every query has its key size.
+    async fn check_queries(&mut self, database: &str, queries: &[Vec<f32>]) -> Result<(), ExecutionError> {
+        let target_size = match self.storage.get_database(database).await {
+            None => { return Err(ExecutionError::DatabaseDoesNotExist { database: database.into() }); }
+            Some(c) => { c }
+        }.get_key_size();
+        for q in queries.iter() {
+            if target_size != q.len() as u32 {
+                return Err(ExecutionError::SizeMismatch { vector: "query", expected: target_size, got: q.len() as u32 });
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates a bucket configured by `properties`. Elements are stored as the database's default type unless `dtype` is given.
+    async fn create_bucket(&mut self, bucket_name: &str, database: &str, properties: &PropertyList) -> Result<(), ExecutionError> {
+        match self.storage.get_database(database).await {
             None => { Err(ExecutionError::DatabaseDoesNotExist { database: database.into() }) }
             Some(db) => {
-                db.create_bucket(bucket_name).await.unwrap();
+                if db.get_bucket(bucket_name).await.is_some() {
+                    return Err(ExecutionError::EntityAlreadyExists { name: bucket_name.into(), ty: EntityType::Bucket });
+                }
+                let dtype = get_dtype_property(properties)?.unwrap_or(db.get_configuration().dtype);
+                let key_quantization = get_key_quantization_property(properties)?.unwrap_or_default();
+                let hnsw = get_hnsw_property(properties)?;
+                let position = get_position_property(properties)?.unwrap_or_default();
+                let compaction_threshold = get_positive_float_property(properties, "compaction_threshold")?;
+                if let Some(threshold) = compaction_threshold.filter(|&t| t > 1.) {
+                    return Err(ExecutionError::InvalidPropertyValue { property: "compaction_threshold", value: threshold.to_string(), expected: "at most 1" });
+                }
+                let max_entries = get_positive_integer_property(properties, "max_entries")?.map(|x| x as usize);
+                let eviction = get_eviction_property(properties)?;
+                if eviction.is_some() && max_entries.is_none() {
+                    return Err(ExecutionError::PropertyNotApplicable { property: "eviction", context: "with max_entries" });
+                }
+                db.create_bucket(bucket_name, BucketConfiguration {
+                    dtype,
+                    key_quantization,
+                    pq: None,
+                    hnsw,
+                    ivf: None,
+                    position,
+                    compaction_threshold,
+                    max_entries,
+                    eviction: eviction.unwrap_or_default(),
+                    ttl_seconds: get_positive_float_property(properties, "ttl_seconds")?,
+                }).await?;
                 Ok(())
             }
         }
     }
-    async fn scan(&mut self, queries: Vec<Vec<f32>>, bucket: &str, database: &str) -> Result<Vec<Vec<f32>>, ExecutionError> {
-        match self.storage.get_database(database).await.unwrap() {
+    /// Attends `queries` over the bucket. Unless `batch_rows` option is given, batch size is derived from the scan memory budget.
+    /// Returns the entries with the highest logits along with the attended vectors if `weights` option is given.
+    /// Deleted entries are skipped, as are entries that do not match `filter` if one is given.
+    async fn scan(&mut self, queries: Vec<Vec<f32>>, bucket_name: &str, database: &str, options: ScanOptions, filter: Option<&Filter>) -> Result<Output, ExecutionError> {
+        match self.storage.get_database(database).await {
             None => { Err(ExecutionError::DatabaseDoesNotExist { database: database.into() }) }
             Some(db) => {
-                match db.get_bucket(bucket).await.unwrap() {
-                    None => { Err(ExecutionError::BucketDoesNotExist { database: database.into(), bucket: bucket.into() }) }
+                let db_conf = *db.get_configuration();
+                match db.get_bucket(bucket_name).await {
+                    None => { Err(ExecutionError::BucketDoesNotExist { database: database.into(), bucket: bucket_name.into() }) }
                     Some(bucket) => {
-                        if queries.len() == 0 {
-                            return Ok(vec![]);
+                        if !bucket.has_keys(options.source) {
+                            let keys = if options.source == KeySource::Pq { "pq" } else { "int8" };
+                            return Err(ExecutionError::MissingKeys { bucket: bucket_name.into(), keys });
+                        }
+                        let heads = match options.heads {
+                            None => db_conf.heads,
+                            Some(heads) => check_heads(heads, db_conf.key_size as usize, db_conf.value_size() as usize)?,
+                        };
+                        let scoring = Scoring {
+                            score: options.score.unwrap_or(db_conf.score),
+                            temperature: options.temperature.unwrap_or(db_conf.temperature),
+                            heads,
+                        };
+                        // The HNSW graph and product quantization codes only approximate dot products of whole keys.
+                        if !scoring.ranks_by_dot() && options.source == KeySource::Pq {
+                            return Err(ExecutionError::PropertyNotApplicable { property: "key_precision = pq", context: "with dot or scaled_dot score" });
+                        }
+                        if !scoring.ranks_by_dot() && options.index == Some(ScanIndex::Hnsw) {
+                            return Err(ExecutionError::PropertyNotApplicable { property: "index = hnsw", context: "with dot or scaled_dot score" });
+                        }
+                        if heads > 1 && options.source == KeySource::Pq {
+                            return Err(ExecutionError::PropertyNotApplicable { property: "key_precision = pq", context: "with a single head" });
+                        }
+                        if heads > 1 && options.index == Some(ScanIndex::Hnsw) {
+                            return Err(ExecutionError::PropertyNotApplicable { property: "index = hnsw", context: "with a single head" });
+                        }
+                        // Indexes and product quantization codes know nothing about positions of entries.
+                        let position = options.position.unwrap_or(bucket.configuration().position);
+                        if position != Position::None && options.index == Some(ScanIndex::Hnsw) {
+                            return Err(ExecutionError::PropertyNotApplicable { property: "index = hnsw", context: "with position = none" });
+                        }
+                        if let Position::Rope { .. } = position {
+                            if options.source == KeySource::Pq {
+                                return Err(ExecutionError::PropertyNotApplicable { property: "key_precision = pq", context: "without position = rope" });
+                            }
+                            if options.index == Some(ScanIndex::Ivf) {
+                                return Err(ExecutionError::PropertyNotApplicable { property: "index = ivf", context: "without position = rope" });
+                            }
+                            if !(db_conf.key_size as usize / heads).is_multiple_of(2) {
+                                return Err(ExecutionError::PropertyNotApplicable { property: "position = rope", context: "with an even number of key elements per head" });
+                            }
+                        }
+                        let mask = bucket.mask(filter).await?;
+                        let index = match options.index {
+                            Some(index) => index,
+                            // The graph would find entries that may be filtered out or deleted.
+                            None if options.top_k.is_some() && bucket.has_hnsw() && scoring.ranks_by_dot() && heads == 1 && position == Position::None && mask.is_none() => ScanIndex::Hnsw,
+                            None => ScanIndex::None,
+                        };
+                        if index == ScanIndex::Hnsw && !bucket.has_hnsw() || index == ScanIndex::Ivf && bucket.ivf().is_none() {
+                            return Err(ExecutionError::MissingIndex { bucket: bucket_name.into() });
+                        }
+                        if queries.is_empty() {
+                            return Ok(Output::Vectors(vec![]));
                         }
                         let q_shape = (queries.len(), queries[0].len());
                         let q_vec: Vec<f32> = queries.into_iter().flatten().collect();
-                        let q = Array2::from_shape_vec(q_shape, q_vec).unwrap();
-                        let mut res: Array2<f32> = Array2::from_elem(q_shape, 0.);
-                        let batch_size = num_cpus::get() * 1024;
-                        bucket.reduce_kv_batched(&mut res, batch_size, compute_cross_attention(512, &q)).await;
-                        Ok(res.rows().into_iter().map(|r| r.to_vec()).collect())
+                        let q = Array2::from_shape_vec(q_shape, q_vec).expect("Query shape is validated by the caller");
+                        let executor = &self.executor;
+                        let shape = ScanShape {
+                            queries: q_shape.0,
+                            key_size: db_conf.key_size as usize,
+                            value_size: db_conf.value_size() as usize,
+                            heads,
+                            threads: executor.threads(),
+                            key_row_size: bucket.key_row_size(options.source),
+                            widened_keys: match options.source {
+                                // Rotary embeddings rotate a copy of the keys.
+                                KeySource::Exact => bucket.dtype() != DType::F32 || matches!(position, Position::Rope { .. }),
+                                KeySource::Int8 => true,
+                                KeySource::Pq => false,
+                            },
+                            value_row_size: bucket.value_row_size(),
+                            // Either entries held back for rescoring or the top-k ones, the two are never combined.
+                            candidates: options.top_k.unwrap_or(options.rescore),
+                            lookup_tables: bucket.product_quantizer().filter(|_| options.source == KeySource::Pq).map_or(0, |pq| {
+                                let conf = pq.configuration();
+                                q_shape.0 * conf.subspaces * conf.centroids * size_of::<f32>()
+                            }),
+                            buffered_batches: match self.scan_io {
+                                ScanIo::Mmap => 0,
+                                // Batches waiting in the pipeline, the one being read and the one being attended.
+                                ScanIo::Read => self.prefetch_depth.max(1) + 2,
+                            },
+                        };
+                        let rows = bucket.rows().await.map_err(StorageError::from)?;
+                        let (batch_rows, required) = plan_batches(&shape, rows, options.batch_rows, self.scan_memory_budget);
+                        if required > self.scan_memory_budget {
+                            return Err(ExecutionError::MemoryBudgetExceeded { required, budget: self.scan_memory_budget });
+                        }
+                        let positions = Positions {
+                            position,
+                            entries: rows,
+                            timestamps: match position {
+                                Position::Decay { .. } => bucket.timestamps().to_vec(),
+                                _ => vec![],
+                            },
+                            now: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64),
+                        };
+
+                        let tracks_usage = bucket.configuration().eviction.tracks_usage();
+                        let scan = BucketScan {
+                            executor,
+                            q: &q,
+                            value_size: shape.value_size,
+                            batch_rows,
+                            scan_io: self.scan_io,
+                            prefetch_depth: self.prefetch_depth,
+                            options,
+                            contributors: options.weights.unwrap_or(0).max(if tracks_usage { USAGE_CONTRIBUTORS } else { 0 }),
+                            index,
+                            scoring,
+                            positions,
+                            mask,
+                        };
+                        let state = match bucket.dtype() {
+                            DType::F32 => scan.run::<f32>(bucket).await?,
+                            DType::F16 => scan.run::<f16>(bucket).await?,
+                            DType::BF16 => scan.run::<bf16>(bucket).await?,
+                        };
+                        if tracks_usage {
+                            let received: Vec<(usize, f32)> = state.contributions().iter().flatten().map(|c| (c.row, c.weight)).collect();
+                            bucket.record_usage(&received);
+                        }
+                        let contributions = options.weights.map(|weights| {
+                            let contributions: Vec<Vec<Contribution>> = state.contributions().into_iter().map(|mut c| {
+                                c.truncate(weights);
+                                c
+                            }).collect();
+                            (contributions, state.entropy().to_vec())
+                        });
+                        let vectors = state.finish().rows().into_iter().map(|r| r.to_vec()).collect();
+                        Ok(match contributions {
+                            None => Output::Vectors(vectors),
+                            Some((contributions, entropy)) => {
+                                let metadata = read_metadata(bucket, &contributions, |c| c.row).await?;
+                                Output::Weights { vectors, contributions, entropy, metadata }
+                            }
+                        })
                     }
                 }
             }
         }
     }
 
-    async fn insert(&mut self, data: Vec<(Vec<f32>, Vec<f32>)>, bucket: &str, database: &str) {}
+    /// Finds entries of the bucket with the best scores for every query by scanning all of them.
+    async fn search(&mut self, queries: Vec<Vec<f32>>, bucket_name: &str, database: &str, options: SearchOptions) -> Result<Output, ExecutionError> {
+        let db = self.storage.get_database(database).await.ok_or_else(|| ExecutionError::DatabaseDoesNotExist { database: database.into() })?;
+        let (key_size, value_size) = (db.get_key_size() as usize, db.get_value_size() as usize);
+        let bucket = db.get_bucket(bucket_name).await.ok_or_else(|| ExecutionError::BucketDoesNotExist { database: database.into(), bucket: bucket_name.into() })?;
+        if queries.is_empty() {
+            return Ok(Output::Neighbours { neighbours: vec![], values: options.values, metadata: None });
+        }
+        let q_shape = (queries.len(), queries[0].len());
+        let q = Array2::from_shape_vec(q_shape, queries.into_iter().flatten().collect()).expect("Query shape is validated by the caller");
+        let shape = ScanShape {
+            queries: q_shape.0,
+            key_size,
+            value_size,
+            heads: 1,
+            threads: self.executor.threads(),
+            key_row_size: bucket.key_row_size(KeySource::Exact),
+            widened_keys: bucket.dtype() != DType::F32,
+            value_row_size: bucket.value_row_size(),
+            candidates: options.k,
+            lookup_tables: 0,
+            buffered_batches: match self.scan_io {
+                ScanIo::Mmap => 0,
+                ScanIo::Read => self.prefetch_depth.max(1) + 2,
+            },
+        };
+        let rows = bucket.rows().await.map_err(StorageError::from)?;
+        let (batch_rows, required) = plan_batches(&shape, rows, options.batch_rows, self.scan_memory_budget);
+        if required > self.scan_memory_budget {
+            return Err(ExecutionError::MemoryBudgetExceeded { required, budget: self.scan_memory_budget });
+        }
+
+        let scan = BucketScan {
+            executor: &self.executor,
+            q: &q,
+            value_size,
+            batch_rows,
+            scan_io: self.scan_io,
+            prefetch_depth: self.prefetch_depth,
+            options: ScanOptions { batch_rows: options.batch_rows, ..Default::default() },
+            contributors: 0,
+            index: ScanIndex::None,
+            scoring: Scoring { score: Score::Dot, temperature: 1., heads: 1 },
+            positions: Positions::default(),
+            mask: bucket.mask(None).await?,
+        };
+        let mut neighbours = match bucket.dtype() {
+            DType::F32 => scan.search::<f32>(bucket, options).await?,
+            DType::F16 => scan.search::<f16>(bucket, options).await?,
+            DType::BF16 => scan.search::<bf16>(bucket, options).await?,
+        };
+        for neighbour in neighbours.iter_mut().flatten() {
+            neighbour.score = options.metric.report(neighbour.score);
+        }
+        let metadata = read_metadata(bucket, &neighbours, |n| n.row).await?;
+        Ok(Output::Neighbours { neighbours, values: options.values, metadata })
+    }
+
+    /// Trains a product quantizer on keys of the bucket and compresses all of its keys with it.
+    async fn compress(&mut self, bucket_name: &str, database: &str, properties: &PropertyList) -> Result<(), ExecutionError> {
+        let db = self.storage.get_database(database).await.ok_or_else(|| ExecutionError::DatabaseDoesNotExist { database: database.into() })?;
+        let key_size = db.get_key_size() as usize;
+        let bucket = db.get_bucket(bucket_name).await.ok_or_else(|| ExecutionError::BucketDoesNotExist { database: database.into(), bucket: bucket_name.into() })?;
+
+        // By default every subspace covers at least 8 elements of a key.
+        let subspaces = match get_positive_integer_property(properties, "subspaces")? {
+            Some(subspaces) => subspaces as usize,
+            None => (1..=key_size).rev().find(|m| key_size.is_multiple_of(*m) && key_size / m >= 8).unwrap_or(1),
+        };
+        if !key_size.is_multiple_of(subspaces) {
+            return Err(ExecutionError::InvalidPropertyValue { property: "subspaces", value: subspaces.to_string(), expected: "a divisor of key_size" });
+        }
+        let centroids = get_positive_integer_property(properties, "centroids")?.map_or(ProductQuantizer::MAX_CENTROIDS, |x| x as usize);
+        if centroids > ProductQuantizer::MAX_CENTROIDS {
+            return Err(ExecutionError::InvalidPropertyValue { property: "centroids", value: centroids.to_string(), expected: "at most 256" });
+        }
+        let iterations = get_positive_integer_property(properties, "iterations")?.map_or(DEFAULT_PQ_ITERATIONS, |x| x as usize);
+        let sample = get_positive_integer_property(properties, "sample")?.map_or(DEFAULT_PQ_SAMPLE, |x| x as usize);
+
+        let keys = bucket.sample_keys(sample).await?;
+        if keys.nrows() == 0 {
+            return Err(ExecutionError::EmptyBucket { bucket: bucket_name.into() });
+        }
+        let pq = self.executor.install(|| ProductQuantizer::train(keys.view(), subspaces, centroids, iterations));
+        bucket.compress_pq(pq).await?;
+        Ok(())
+    }
+
+    /// Builds an index over all entries of the bucket, replacing the previous index of the same kind.
+    /// Inverted file centroids are trained with k-means on a sample of keys, by default `sqrt(entries)` of them.
+    async fn build_index(&mut self, bucket_name: &str, database: &str, properties: &PropertyList) -> Result<(), ExecutionError> {
+        let db = self.storage.get_database(database).await.ok_or_else(|| ExecutionError::DatabaseDoesNotExist { database: database.into() })?;
+        let bucket = db.get_bucket(bucket_name).await.ok_or_else(|| ExecutionError::BucketDoesNotExist { database: database.into(), bucket: bucket_name.into() })?;
+        match get_index_kind_property(properties)? {
+            IndexKind::Hnsw(conf) => bucket.build_hnsw(conf).await?,
+            IndexKind::Ivf => {
+                let rows = bucket.rows().await.map_err(StorageError::from)?;
+                if rows == 0 {
+                    return Err(ExecutionError::EmptyBucket { bucket: bucket_name.into() });
+                }
+                let lists = get_positive_integer_property(properties, "lists")?.map_or_else(|| (rows as f64).sqrt().round() as usize, |x| x as usize);
+                let iterations = get_positive_integer_property(properties, "iterations")?.map_or(DEFAULT_IVF_ITERATIONS, |x| x as usize);
+                let sample = get_positive_integer_property(properties, "sample")?.map_or(DEFAULT_IVF_SAMPLE, |x| x as usize);
+                let keys = bucket.sample_keys(sample).await?;
+                let index = self.executor.install(|| IvfIndex::train(keys.view(), lists, iterations));
+                bucket.build_ivf(index).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Lists configuration of the database, or of the bucket and sizes of its files if `bucket_name` is given.
+    async fn describe(&mut self, database: &str, bucket_name: Option<&str>) -> Result<Vec<(&'static str, String)>, ExecutionError> {
+        let db = self.storage.get_database(database).await.ok_or_else(|| ExecutionError::DatabaseDoesNotExist { database: database.into() })?;
+        let Some(bucket_name) = bucket_name else {
+            let conf = db.get_configuration();
+            return Ok(vec![
+                ("key_size", conf.key_size.to_string()),
+                ("value_size", conf.value_size().to_string()),
+                ("dtype", conf.dtype.name().to_string()),
+                ("score", conf.score.name().to_string()),
+                ("temperature", conf.temperature.to_string()),
+                ("heads", conf.heads.to_string()),
+                ("projection_version", conf.projection_version.to_string()),
+                ("buckets", db.bucket_names().join(", ")),
+            ]);
+        };
+        let bucket = db.get_bucket(bucket_name).await.ok_or_else(|| ExecutionError::BucketDoesNotExist { database: database.into(), bucket: bucket_name.into() })?;
+        let rows = bucket.rows().await.map_err(StorageError::from)?;
+        let conf = bucket.configuration();
+        let keys_bytes = rows * bucket.key_row_size(KeySource::Exact);
+        let mut properties = vec![
+            ("entries", rows.to_string()),
+            ("dtype", conf.dtype.name().to_string()),
+            ("key_quantization", conf.key_quantization.name().to_string()),
+            ("keys_bytes", keys_bytes.to_string()),
+            ("values_bytes", (rows * bucket.value_row_size()).to_string()),
+        ];
+        if bucket.has_keys(KeySource::Int8) {
+            properties.push(("int8_keys_bytes", (rows * bucket.key_row_size(KeySource::Int8)).to_string()));
+        }
+        if bucket.has_metadata() {
+            properties.push(("metadata_bytes", bucket.metadata_bytes().to_string()));
+        }
+        properties.push(("deleted_entries", bucket.deleted_entries().to_string()));
+        properties.push(("compaction_threshold", conf.compaction_threshold().to_string()));
+        if let Some(ttl) = conf.ttl_seconds {
+            properties.push(("ttl_seconds", ttl.to_string()));
+        }
+        properties.push(("expired_entries", bucket.expired_entries().to_string()));
+        if let Some(max_entries) = conf.max_entries {
+            properties.extend([("max_entries", max_entries.to_string()), ("eviction", conf.eviction.name().to_string())]);
+        }
+        if let Some(pq) = bucket.product_quantizer() {
+            let pq_conf = pq.configuration();
+            // Codebooks are shared by all entries, so the ratio is that of a single key to its codes.
+            let ratio = bucket.key_row_size(KeySource::Exact) as f64 / bucket.key_row_size(KeySource::Pq) as f64;
+            properties.extend([
+                ("pq_subspaces", pq_conf.subspaces.to_string()),
+                ("pq_centroids", pq_conf.centroids.to_string()),
+                ("pq_codes_bytes", (rows * bucket.key_row_size(KeySource::Pq)).to_string()),
+                ("pq_compression_ratio", format!("{ratio:.1}")),
+            ]);
+        }
+        if let Some(hnsw) = conf.hnsw {
+            properties.extend([("hnsw_m", hnsw.m.to_string()), ("hnsw_ef_construction", hnsw.ef_construction.to_string())]);
+        }
+        if let Some(ivf) = bucket.ivf() {
+            properties.push(("ivf_lists", ivf.configuration().lists.to_string()));
+        }
+        properties.push(("position", conf.position.name().to_string()));
+        match conf.position {
+            Position::None => {}
+            Position::Alibi { slope } => properties.push(("alibi_slope", slope.to_string())),
+            Position::Rope { base } => properties.push(("rope_base", base.to_string())),
+            Position::Decay { half_life } => properties.push(("decay_half_life", half_life.to_string())),
+        }
+        Ok(properties)
+    }
+
+    /// Inserts entries living for `ttl` seconds if given, replacing those with the same external ids, and evicts entries
+    /// beyond capacity of the bucket, expired ones first. Returns the numbers of inserted, replaced and evicted
+    /// entries and whether deleted entries reach the compaction threshold of the bucket.
+    async fn upsert(&mut self, data: Vec<(Vec<f32>, Vec<f32>)>, metadata: Option<Vec<Metadata>>, ids: Vec<String>, ttl: Option<f32>, bucket_name: &str, database: &str) -> Result<Vec<(&'static str, String)>, ExecutionError> {
+        let db = self.storage.get_database(database).await.ok_or_else(|| ExecutionError::DatabaseDoesNotExist { database: database.into() })?;
+        let bucket = db.get_bucket(bucket_name).await.ok_or_else(|| ExecutionError::BucketDoesNotExist { database: database.into(), bucket: bucket_name.into() })?;
+        let inserted = data.len();
+        let start = bucket.rows().await.map_err(StorageError::from)?;
+        let replaced = bucket.upsert_kv(data, metadata, ids, ttl).await?;
+        let evicted = bucket.evict(start).await?;
+        Ok(vec![
+            ("inserted", inserted.to_string()),
+            ("replaced", replaced.to_string()),
+            ("evicted", evicted.to_string()),
+            ("needs_compaction", bucket.needs_compaction().to_string()),
+        ])
+    }
+
+    /// Inserts entries living for `ttl` seconds if given and evicts entries beyond capacity of the bucket, expired ones first.
+    async fn insert(&mut self, data: Vec<(Vec<f32>, Vec<f32>)>, metadata: Option<Vec<Metadata>>, ttl: Option<f32>, bucket_name: &str, database: &str) -> Result<(), ExecutionError> {
+        let db = self.storage.get_database(database).await.ok_or_else(|| ExecutionError::DatabaseDoesNotExist { database: database.into() })?;
+        let bucket = db.get_bucket(bucket_name).await.ok_or_else(|| ExecutionError::BucketDoesNotExist { database: database.into(), bucket: bucket_name.into() })?;
+        let start = bucket.rows().await.map_err(StorageError::from)?;
+        bucket.insert_kv(data, metadata, ttl).await?;
+        bucket.evict(start).await?;
+        Ok(())
+    }
+}
+
+/// Reads metadata of the entries returned for every query, none if no entry of the bucket has metadata.
+async fn read_metadata<T>(bucket: &Bucket, entries: &[Vec<T>], row: fn(&T) -> usize) -> Result<Option<Vec<Vec<Metadata>>>, StorageError> {
+    if !bucket.has_metadata() {
+        return Ok(None);
+    }
+    let mut metadata = Vec::with_capacity(entries.len());
+    for entries in entries {
+        metadata.push(bucket.read_metadata(&entries.iter().map(row).collect::<Vec<usize>>()).await?);
+    }
+    Ok(Some(metadata))
+}
+
+/// Writes a length-prefixed response to the client.
+async fn respond(stream: &mut TcpStream, content: &str) -> Result<(), std::io::Error> {
+    stream.write_all(&(content.len() as u32).to_le_bytes()).await?;
+    stream.write_all(content.as_bytes()).await?;
+    stream.flush().await
+}
+
+/// Reads a single request from the client, executes it and writes the response.
+/// Any failure of the request itself is reported to the client; only connection errors are returned.
+async fn handle_connection(engine: &mut Engine, stream: &mut TcpStream) -> Result<(), std::io::Error> {
+    let mut content_size = [0u8; 4];
+    stream.read_exact(&mut content_size).await?;
+    let content_size = u32::from_le_bytes(content_size);
+    let mut content = vec![0u8; content_size as usize];
+    stream.read_exact(&mut content).await?;
+    let commands_text = match String::from_utf8(content) {
+        Ok(c) => { c }
+        Err(err) => {
+            return respond(stream, &err.to_string()).await;
+        }
+    };
+    println!("{commands_text}");
+    let commands = match command::parse_commands(&commands_text) {
+        Ok(c) => { c }
+        Err(err) => {
+            return respond(stream, &err.to_string()).await;
+        }
+    };
+    let mut res: Option<Output> = None;
+    for command in commands {
+        res = match engine.execute(command).await {
+            Ok(c) => { c }
+            Err(err) => {
+                return respond(stream, &err.to_string()).await;
+            }
+        };
+    }
+
+    let mut result = String::new();
+
+    if let Some(res) = res {
+        result.push_str(&res.to_string());
+    }
+    result.push_str("DONE.");
+    respond(stream, &result).await
 }
 
 #[tokio::main]
@@ -330,6 +1571,7 @@ async fn main() -> anyhow::Result<()> {
             &args.config,
             serde_json::to_string_pretty(&Configuration {
                 data_directory: PathBuf::from("./data"),
+                ..Default::default()
             })?,
         )
             .await?;
@@ -342,7 +1584,9 @@ async fn main() -> anyhow::Result<()> {
     )
         .expect("Invalid configuration file");
 
-    let mut engine = Engine::new(conf).await;
+    let expiration_interval = conf.expiration_interval.unwrap_or(DEFAULT_EXPIRATION_INTERVAL);
+    anyhow::ensure!(expiration_interval > 0. && expiration_interval.is_finite(), "Expiration interval must be a positive number of seconds");
+    let mut engine = Engine::new(conf).await?;
 
     if let Some(init_path) = args.init {
         let content = tokio::fs::read_to_string(init_path).await?;
@@ -353,104 +1597,34 @@ async fn main() -> anyhow::Result<()> {
     }
 
 
-    let listener = TcpListener::bind("127.0.0.1:7878").await.unwrap();
+    let listener = TcpListener::bind("127.0.0.1:7878").await?;
+    // Expired entries are swept and buckets compacted between connections, as commands are.
+    let mut sweeps = tokio::time::interval(Duration::from_secs_f32(expiration_interval));
+    sweeps.set_missed_tick_behavior(MissedTickBehavior::Delay);
     loop {
-        let (mut stream, address) = listener.accept().await?;
-        let mut content_size = [0u8; 4];
-        match stream.read_exact(&mut content_size).await {
-            Ok(c) => c,
-            Err(err) => {
-                println!("Connection to {} lost.", address);
-                continue;
-            }
-        };
-        let content_size = u32::from_le_bytes(content_size);
-        let mut content = Vec::from_iter((0..content_size).map(|_| 0u8));
-        match stream.read_exact(&mut content).await {
-            Ok(c) => c,
-            Err(err) => {
-                println!("Connection to {} lost.", address);
-                continue;
-            }
-        };
-        let commands_text = String::from_utf8(content)?;
-        println!("{commands_text}");
-        let commands = match command::parse_commands(&commands_text) {
-            Ok(c) => { c }
-            Err(err) => {
-                let content = err.to_string();
-                match stream.write(&(content.as_bytes().len() as u32).to_le_bytes()).await {
-                    Ok(c) => { c }
-                    Err(_) => {
-                        println!("Connection to {} lost.", address);
-                        continue;
-                    }
-                };
-                match stream.write(content.as_bytes()).await {
-                    Ok(c) => (),
-                    Err(_) => {
-                        println!("Connection to {} lost.", address);
-                        continue;
-                    }
-                }
-                continue;
-            }
-        };
-        let mut res: Option<Vec<Vec<f32>>> = None;
-        let mut error = false;
-        for command in commands {
-            res = match engine.execute(command).await {
-                Ok(c) => { c }
-                Err(err) => {
-                    let content = err.to_string();
-                    match stream.write(&(content.as_bytes().len() as u32).to_le_bytes()).await {
-                        Ok(c) => { c }
-                        Err(_) => {
-                            println!("Connection to {} lost.", address);
-                            continue;
-                        }
-                    };
-                    match stream.write(content.as_bytes()).await {
-                        Ok(c) => (),
-                        Err(_) => {
-                            println!("Connection to {} lost.", address);
-                            continue;
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = sweeps.tick() => {
+                match engine.sweep().await {
+                    Ok(compacted) => {
+                        for (database, bucket, compaction) in compacted {
+                            println!("Compacted bucket {bucket} inside {database}: {} of {} entries remain.", compaction.previous_ids.len(), compaction.entries);
                         }
                     }
-                    error = true;
-                    continue;
+                    Err(err) => println!("Unable to sweep buckets: {err}"),
                 }
-            };
-        }
-
-        if error {
-            continue
-        }
-
-        let mut result = String::new();
-
-        if let Some(res) = res {
-            result.extend(format!("({})\n", res.into_iter().map(|v| format!("[{}]", v.into_iter().map(|r| r.to_string()).collect::<Vec<String>>().join(", "))).collect::<Vec<String>>().join(", ")).chars());
-        }
-        result.extend("DONE.".chars());
-        match stream.write(&(result.len() as u32).to_le_bytes()).await {
-            Ok(c) => (),
-            Err(_) => {
-                println!("Connection to {address} lost");
                 continue;
             }
         };
-
-        match stream.write(result.as_bytes()).await {
-            Ok(c) => (),
-            Err(_) => {
-                println!("Connection to {address} lost.");
+        let (mut stream, address) = match accepted {
+            Ok(c) => { c }
+            Err(err) => {
+                println!("Unable to accept connection: {err}");
                 continue;
             }
         };
-
-        stream.flush().await?;
+        if handle_connection(&mut engine, &mut stream).await.is_err() {
+            println!("Connection to {address} lost.");
+        }
     }
-
-    Ok(())
 }
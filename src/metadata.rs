@@ -0,0 +1,144 @@
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use serde::{Deserialize, Serialize};
+
+/// Value of a metadata field.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Value {
+    Integer(i64),
+    Float(f64),
+    String(String),
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Integer(value) => write!(f, "{value}"),
+            // Debug formatting keeps the decimal point, so the value is read back as a float.
+            Value::Float(value) => write!(f, "{value:?}"),
+            Value::String(value) => write!(f, "'{}'", value.replace('\'', "''")),
+        }
+    }
+}
+
+impl Value {
+    /// Orders values of the same kind, integers and floats being compared as numbers. Values of different kinds are not ordered.
+    fn compare(&self, other: &Value) -> Option<Ordering> {
+        match (self, other) {
+            (Value::Integer(a), Value::Integer(b)) => Some(a.cmp(b)),
+            (Value::Integer(a), Value::Float(b)) => (*a as f64).partial_cmp(b),
+            (Value::Float(a), Value::Integer(b)) => a.partial_cmp(&(*b as f64)),
+            (Value::Float(a), Value::Float(b)) => a.partial_cmp(b),
+            (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+            _ => None,
+        }
+    }
+}
+
+/// Named fields attached to an entry, such as id of the source document or a text snippet.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Metadata(pub BTreeMap<String, Value>);
+
+impl Metadata {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn get(&self, field: &str) -> Option<&Value> {
+        self.0.get(field)
+    }
+
+    /// Decodes metadata of one entry. Entries without metadata are stored as no bytes at all.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.is_empty() {
+            return Some(Self::default());
+        }
+        bincode::deserialize(bytes).ok()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        if self.is_empty() {
+            return vec![];
+        }
+        bincode::serialize(self).expect("Metadata is always serializable")
+    }
+}
+
+/// Formats fields the way they are given to `INSERT`: `{name = value, ...}`.
+impl Display for Metadata {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let fields: Vec<String> = self.0.iter().map(|(name, value)| format!("{name} = {value}")).collect();
+        write!(f, "{{{}}}", fields.join(", "))
+    }
+}
+
+/// Operator comparing a field with a value.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+impl Comparison {
+    pub fn parse(operator: &str) -> Option<Self> {
+        match operator {
+            "=" => Some(Comparison::Equal),
+            "!=" | "<>" => Some(Comparison::NotEqual),
+            "<" => Some(Comparison::Less),
+            "<=" => Some(Comparison::LessOrEqual),
+            ">" => Some(Comparison::Greater),
+            ">=" => Some(Comparison::GreaterOrEqual),
+            _ => None,
+        }
+    }
+
+    fn holds(&self, ordering: Ordering) -> bool {
+        match self {
+            Comparison::Equal => ordering.is_eq(),
+            Comparison::NotEqual => ordering.is_ne(),
+            Comparison::Less => ordering.is_lt(),
+            Comparison::LessOrEqual => ordering.is_le(),
+            Comparison::Greater => ordering.is_gt(),
+            Comparison::GreaterOrEqual => ordering.is_ge(),
+        }
+    }
+}
+
+/// Condition on metadata of entries given by a `WHERE` clause. Field `id` is the number of the entry in its bucket.
+/// Comparisons of a missing field or of a value of another kind never hold.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+    Compare { field: String, comparison: Comparison, value: Value },
+    /// Field equals one of the values.
+    In { field: String, values: Vec<Value> },
+    Not(Box<Filter>),
+    And(Box<Filter>, Box<Filter>),
+    Or(Box<Filter>, Box<Filter>),
+}
+
+impl Filter {
+    /// Whether entry number `id` with `metadata` meets the condition.
+    pub fn matches(&self, id: usize, metadata: &Metadata) -> bool {
+        let field = |name: &str| match name {
+            "id" => Some(Cow::Owned(Value::Integer(id as i64))),
+            name => metadata.get(name).map(Cow::Borrowed),
+        };
+        match self {
+            Filter::Compare { field: name, comparison, value } => {
+                field(name).and_then(|field| field.compare(value)).is_some_and(|ordering| comparison.holds(ordering))
+            }
+            Filter::In { field: name, values } => {
+                field(name).is_some_and(|field| values.iter().any(|value| field.compare(value).is_some_and(Ordering::is_eq)))
+            }
+            Filter::Not(filter) => !filter.matches(id, metadata),
+            Filter::And(a, b) => a.matches(id, metadata) && b.matches(id, metadata),
+            Filter::Or(a, b) => a.matches(id, metadata) || b.matches(id, metadata),
+        }
+    }
+}
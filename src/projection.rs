@@ -0,0 +1,58 @@
+use ndarray::{Array2, ArrayView2};
+use serde::{Deserialize, Serialize};
+
+/// Matrix rows of vectors are multiplied by, mapping vectors of `inputs` elements to vectors of `outputs` elements.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Matrix {
+    inputs: usize,
+    outputs: usize,
+    /// Elements in row-major order, one row per input element.
+    elements: Vec<f32>,
+}
+
+impl Matrix {
+    /// Builds the matrix from its rows. Returns `None` if there are no rows or they differ in size.
+    pub fn from_rows(rows: Vec<Vec<f32>>) -> Option<Self> {
+        let outputs = rows.first()?.len();
+        if outputs == 0 || rows.iter().any(|row| row.len() != outputs) {
+            return None;
+        }
+        Some(Self { inputs: rows.len(), outputs, elements: rows.into_iter().flatten().collect() })
+    }
+
+    pub fn inputs(&self) -> usize {
+        self.inputs
+    }
+
+    pub fn outputs(&self) -> usize {
+        self.outputs
+    }
+
+    /// Projects every row of `x`, which has `inputs` columns.
+    pub fn apply(&self, x: ArrayView2<f32>) -> Array2<f32> {
+        let matrix = ArrayView2::from_shape((self.inputs, self.outputs), &self.elements).expect("Shape is checked on construction");
+        x.dot(&matrix)
+    }
+}
+
+/// Matrices a database projects raw embeddings with: queries of scans by `wq`, keys and values of inserted entries by `wk` and `wv`.
+/// Vectors without a matrix are used as they are.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Projection {
+    pub wq: Option<Matrix>,
+    pub wk: Option<Matrix>,
+    pub wv: Option<Matrix>,
+}
+
+impl Projection {
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let projection: Self = bincode::deserialize(bytes).ok()?;
+        // Elements were not checked against the shape when deserialized.
+        let valid = |matrix: &Option<Matrix>| matrix.as_ref().is_none_or(|m| m.elements.len() == m.inputs * m.outputs);
+        (valid(&projection.wq) && valid(&projection.wk) && valid(&projection.wv)).then_some(projection)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("Projection is always serializable")
+    }
+}
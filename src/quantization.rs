@@ -0,0 +1,154 @@
+use crate::clustering::{kmeans, nearest_centroids};
+use ndarray::{s, Array2, Array3, ArrayView2, Axis};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::mem::size_of;
+
+/// Compressed copy of the keys kept next to the exact ones.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyQuantization {
+    /// Only exact keys are stored.
+    #[default]
+    None,
+    /// Every key is additionally stored as int8 codes with its own scale and zero point.
+    Int8,
+}
+
+impl KeyQuantization {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "none" => Some(KeyQuantization::None),
+            "int8" => Some(KeyQuantization::Int8),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            KeyQuantization::None => "none",
+            KeyQuantization::Int8 => "int8",
+        }
+    }
+}
+
+/// Bytes preceding the codes of every quantized row: `f32` scale and `f32` zero point, little endian.
+const ROW_HEADER: usize = 2 * size_of::<f32>();
+
+/// Size in bytes of a quantized row of `key_size` elements.
+pub fn int8_row_size(key_size: usize) -> usize {
+    ROW_HEADER + key_size
+}
+
+/// Appends `row` quantized to int8 to `out`, each element is restored as `zero_point + scale * code`.
+pub fn quantize_int8(row: &[f32], out: &mut Vec<u8>) {
+    let min = row.iter().copied().fold(f32::INFINITY, f32::min);
+    let max = row.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let scale = (max - min) / 255.;
+    let zero_point = min + 128. * scale;
+    out.extend_from_slice(&scale.to_le_bytes());
+    out.extend_from_slice(&zero_point.to_le_bytes());
+    out.extend(row.iter().map(|x| {
+        let code = if scale > 0. { ((x - zero_point) / scale).round().clamp(-128., 127.) } else { 0. };
+        code as i8 as u8
+    }));
+}
+
+/// Restores a batch of int8 quantized rows to `f32`.
+pub fn dequantize_int8(rows: ArrayView2<u8>) -> Array2<f32> {
+    let size = rows.ncols() - ROW_HEADER;
+    let mut out = Array2::zeros((rows.nrows(), size));
+    for (row, mut out) in rows.rows().into_iter().zip(out.rows_mut()) {
+        let scale = f32::from_le_bytes([row[0], row[1], row[2], row[3]]);
+        let zero_point = f32::from_le_bytes([row[4], row[5], row[6], row[7]]);
+        for (x, &code) in out.iter_mut().zip(row.iter().skip(ROW_HEADER)) {
+            *x = zero_point + scale * code as i8 as f32;
+        }
+    }
+    out
+}
+
+/// Shape of the product quantizer a bucket was compressed with.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PqConfiguration {
+    /// Number of equally sized parts every key is split into, each encoded by one byte.
+    pub subspaces: usize,
+    /// Number of centroids of every subspace, at most 256.
+    pub centroids: usize,
+}
+
+/// Product quantizer: every key is split into subspaces and each part is replaced by the index of its nearest centroid.
+#[derive(Debug, Clone)]
+pub struct ProductQuantizer {
+    conf: PqConfiguration,
+    /// Centroids of every subspace, shaped `(subspaces, centroids, key_size / subspaces)`.
+    codebooks: Array3<f32>,
+}
+
+impl ProductQuantizer {
+    pub const MAX_CENTROIDS: usize = 1 << u8::BITS;
+
+    /// Trains codebooks on `keys` by running `iterations` of k-means in every subspace.
+    /// Fewer centroids are trained if there are less keys than requested centroids.
+    pub fn train(keys: ArrayView2<f32>, subspaces: usize, centroids: usize, iterations: usize) -> Self {
+        let sub_size = keys.ncols() / subspaces;
+        let centroids = centroids.min(keys.nrows()).clamp(1, Self::MAX_CENTROIDS);
+        let trained: Vec<Array2<f32>> = (0..subspaces)
+            .into_par_iter()
+            .map(|m| kmeans(keys.slice(s![.., m * sub_size..(m + 1) * sub_size]), centroids, iterations))
+            .collect();
+        let views: Vec<ArrayView2<f32>> = trained.iter().map(|c| c.view()).collect();
+        Self {
+            conf: PqConfiguration { subspaces, centroids },
+            codebooks: ndarray::stack(Axis(0), &views).expect("Every subspace has the same number of centroids"),
+        }
+    }
+
+    pub fn configuration(&self) -> PqConfiguration {
+        self.conf
+    }
+
+    /// Restores quantizer from codebooks written by [`ProductQuantizer::to_bytes`].
+    pub fn from_bytes(conf: PqConfiguration, key_size: usize, bytes: &[u8]) -> Option<Self> {
+        let values: Vec<f32> = bytes.chunks_exact(size_of::<f32>()).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect();
+        let codebooks = Array3::from_shape_vec((conf.subspaces, conf.centroids, key_size / conf.subspaces), values).ok()?;
+        Some(Self { conf, codebooks })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.codebooks.iter().flat_map(|x| x.to_le_bytes()).collect()
+    }
+
+    /// Encodes every key into `subspaces` bytes, appending them to `out`.
+    pub fn encode(&self, keys: ArrayView2<f32>, out: &mut Vec<u8>) {
+        let sub_size = self.codebooks.dim().2;
+        let mut codes = Array2::<u8>::zeros((keys.nrows(), self.conf.subspaces));
+        for (m, codebook) in self.codebooks.outer_iter().enumerate() {
+            let nearest = nearest_centroids(keys.slice(s![.., m * sub_size..(m + 1) * sub_size]), codebook);
+            codes.column_mut(m).iter_mut().zip(nearest).for_each(|(code, c)| *code = c as u8);
+        }
+        out.extend(codes.iter());
+    }
+
+    /// Dot products of every query part with every centroid of its subspace, shaped `(queries, subspaces, centroids)`.
+    pub fn lookup_tables(&self, q: &Array2<f32>) -> Array3<f32> {
+        let sub_size = self.codebooks.dim().2;
+        let mut tables = Array3::zeros((q.nrows(), self.conf.subspaces, self.conf.centroids));
+        for (m, codebook) in self.codebooks.outer_iter().enumerate() {
+            let dots = q.slice(s![.., m * sub_size..(m + 1) * sub_size]).dot(&codebook.t());
+            tables.slice_mut(s![.., m, ..]).assign(&dots);
+        }
+        tables
+    }
+
+    /// Approximate logits of every query against encoded keys `codes`, one row per query.
+    pub fn logits(tables: &Array3<f32>, codes: ArrayView2<u8>) -> Array2<f32> {
+        let mut logits = Array2::zeros((tables.dim().0, codes.nrows()));
+        for (table, mut row) in tables.outer_iter().zip(logits.rows_mut()) {
+            for (code, logit) in codes.rows().into_iter().zip(row.iter_mut()) {
+                *logit = code.iter().enumerate().map(|(m, &c)| table[[m, c as usize]]).sum();
+            }
+        }
+        logits
+    }
+}
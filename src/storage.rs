@@ -1,15 +1,25 @@
 use std::collections::HashMap;
 use std::error::Error;
-use std::fmt::{Display, Formatter, write};
-use std::io::SeekFrom;
+use std::fmt::{Display, Formatter};
+use std::io::{Read, SeekFrom, Write};
 use std::marker::PhantomData;
 use std::mem::size_of;
 use std::ops::Deref;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use memmap2::Mmap;
+use ndarray::{s, Array2, ArrayView2};
 use serde::{Deserialize, Serialize};
 use tokio::fs::File;
-use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader};
+use crate::attention::{Position, Score};
+use crate::dtype::{DType, StoredElement};
+use crate::hnsw::{HnswConfiguration, HnswIndex, UpperLayers, Vectors};
+use crate::ivf::{IvfConfiguration, IvfIndex};
+use crate::metadata::{Filter, Metadata};
+use crate::projection::Projection;
+use crate::quantization::{int8_row_size, quantize_int8, KeyQuantization, PqConfiguration, ProductQuantizer};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
 
 #[derive(Debug, Copy, Clone)]
 pub struct InvalidLayoutError;
@@ -21,26 +31,40 @@ impl Display for InvalidLayoutError {
 
 impl Error for InvalidLayoutError {}
 
+#[derive(Debug, thiserror::Error)]
+pub enum StorageError {
+    #[error("{0}")]
+    AlreadyExists(#[from] AlreadyExists),
+    #[error("I/O error: {0}")]
+    IOError(#[from] std::io::Error),
+    #[error("Data file has invalid layout: {0}")]
+    InvalidLayout(#[from] InvalidLayoutError),
+    #[error("Configuration file of database {0} is corrupted")]
+    CorruptedConfiguration(String),
+}
 
 pub struct VecView<'parent, T: Sized> {
     v: *const T,
     size: usize,
-    phd: PhantomData<&'parent Vec<u8>>
+    phd: PhantomData<&'parent [u8]>
 }
 
 impl<'p, T: Sized> VecView<'p, T> {
-    pub fn from_vec(vec: &'p Vec<u8>) -> Result<Self, InvalidLayoutError>{
-        if vec.len() % size_of::<T>() != 0 {
+    pub fn from_vec(vec: &'p [u8]) -> Result<Self, InvalidLayoutError>{
+        if !vec.len().is_multiple_of(size_of::<T>()) {
             return Err(InvalidLayoutError)
         }
         Ok(Self {
             v: vec.as_ptr() as *const T,
             size: vec.len() / size_of::<T>(),
-            phd: PhantomData::default(),
+            phd: PhantomData,
         })
     }
+}
 
-    pub fn get(&self) -> &[T] {
+impl<'p, T> VecView<'p, T> {
+    /// Returns the elements borrowed for as long as the underlying bytes are.
+    pub fn as_slice(&self) -> &'p [T] {
         unsafe { std::slice::from_raw_parts(self.v, self.size) }
     }
 }
@@ -55,108 +79,1479 @@ impl<'p, T> Deref for VecView<'p, T> {
 
 #[derive(Clone, Copy, Serialize, Deserialize)]
 pub struct DatabaseConfiguration {
-    pub qkv_vec_size: u32,
+    /// Number of elements of every query and key.
+    #[serde(alias = "qkv_vec_size")]
+    pub key_size: u32,
+    /// Number of elements of every value. Databases created before values could differ from keys leave it out.
+    #[serde(default)]
+    pub value_size: Option<u32>,
+    /// Default element type of buckets created in the database.
+    #[serde(default)]
+    pub dtype: DType,
+    /// Default score of scans over the database.
+    #[serde(default)]
+    pub score: Score,
+    /// Default temperature logits of scans over the database are divided by.
+    #[serde(default = "default_temperature")]
+    pub temperature: f32,
+    /// Default number of heads of scans over the database.
+    #[serde(default = "default_heads")]
+    pub heads: usize,
+    /// Version of the projection matrices in `projection.<version>.bin`, zero if none were set.
+    #[serde(default)]
+    pub projection_version: u32,
+}
+
+fn default_temperature() -> f32 {
+    1.
+}
+
+fn default_heads() -> usize {
+    1
+}
+
+impl DatabaseConfiguration {
+    /// Number of elements of every value, the key size unless configured otherwise.
+    pub fn value_size(&self) -> u32 {
+        self.value_size.unwrap_or(self.key_size)
+    }
+
+    /// Reads configuration from the database directory.
+    /// Databases created before `conf.json` was introduced only have bincode-encoded `conf.bc`.
+    async fn from_disk(data_directory: &Path) -> Result<Self, StorageError> {
+        let corrupted = || StorageError::CorruptedConfiguration(data_directory.display().to_string());
+        let path = data_directory.join("conf.json");
+        if path.exists() {
+            let content = tokio::fs::read_to_string(path).await?;
+            return serde_json::from_str(&content).map_err(|_| corrupted());
+        }
+        let buf = tokio::fs::read(data_directory.join("conf.bc")).await?;
+        let qkv_vec_size: u32 = bincode::deserialize(&buf).map_err(|_| corrupted())?;
+        Ok(Self { key_size: qkv_vec_size, value_size: None, dtype: DType::F32, score: Score::Dot, temperature: default_temperature(), heads: default_heads(), projection_version: 0 })
+    }
+}
+
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+pub struct BucketConfiguration {
+    #[serde(default)]
+    pub dtype: DType,
+    #[serde(default)]
+    pub key_quantization: KeyQuantization,
+    /// Present once the bucket was compressed with a product quantizer.
+    #[serde(default)]
+    pub pq: Option<PqConfiguration>,
+    /// Present if the bucket keeps an HNSW graph over its keys.
+    #[serde(default)]
+    pub hnsw: Option<HnswConfiguration>,
+    /// Present once an inverted file index was built over the keys of the bucket.
+    #[serde(default)]
+    pub ivf: Option<IvfConfiguration>,
+    /// Default position mode of scans over the bucket.
+    #[serde(default)]
+    pub position: Position,
+    /// Share of deleted entries at which sweeps compact the bucket, see [`BucketConfiguration::compaction_threshold`].
+    #[serde(default)]
+    pub compaction_threshold: Option<f32>,
+    /// Number of entries the bucket holds at most, inserts beyond it evict entries chosen by `eviction`.
+    #[serde(default)]
+    pub max_entries: Option<usize>,
+    #[serde(default)]
+    pub eviction: Eviction,
+    /// Seconds entries live for unless inserted with their own `ttl`, they never expire if not present.
+    #[serde(default)]
+    pub ttl_seconds: Option<f32>,
+}
+
+impl BucketConfiguration {
+    pub fn compaction_threshold(&self) -> f32 {
+        self.compaction_threshold.unwrap_or(DEFAULT_COMPACTION_THRESHOLD)
+    }
+}
+
+/// Entries evicted first once a bucket holds more than `max_entries`.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Eviction {
+    /// The oldest entries.
+    #[default]
+    Fifo,
+    /// Entries scans last used the longest time ago, see [`Usage`].
+    Lru,
+    /// Entries that received the least attention weight from scans, see [`Usage`].
+    Lfu,
+}
+
+impl Eviction {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "fifo" => Some(Eviction::Fifo),
+            "lru" => Some(Eviction::Lru),
+            "lfu" => Some(Eviction::Lfu),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Eviction::Fifo => "fifo",
+            Eviction::Lru => "lru",
+            Eviction::Lfu => "lfu",
+        }
+    }
+
+    /// Whether scans have to record usage of the entries they attend to.
+    pub fn tracks_usage(&self) -> bool {
+        *self != Eviction::Fifo
+    }
+}
+
+/// Attention weight a query has to give an entry for the entry to count as used by the scan.
+pub const LRU_USE_WEIGHT: f32 = 0.01;
+
+/// How much scans attended to an entry, stored in `usage.bin` as little endian `u64` followed by little endian `f32`.
+#[derive(Debug, Copy, Clone)]
+struct Usage {
+    /// Milliseconds since the Unix epoch when a query last gave the entry at least [`LRU_USE_WEIGHT`] of its attention,
+    /// insertion time of the entry until then.
+    last_used: u64,
+    /// Attention weight the entry received from all queries and heads of all scans.
+    weight: f32,
+}
+
+impl Usage {
+    const SIZE: usize = size_of::<u64>() + size_of::<f32>();
+
+    fn to_bytes(self) -> [u8; Usage::SIZE] {
+        let mut bytes = [0; Usage::SIZE];
+        bytes[..size_of::<u64>()].copy_from_slice(&self.last_used.to_le_bytes());
+        bytes[size_of::<u64>()..].copy_from_slice(&self.weight.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        let (last_used, weight) = bytes.split_at(size_of::<u64>());
+        Self {
+            last_used: u64::from_le_bytes(last_used.try_into().expect("Usage starts with u64")),
+            weight: f32::from_le_bytes(weight.try_into().expect("Usage ends with f32")),
+        }
+    }
+}
+
+/// Share of deleted entries at which buckets need compaction unless configured otherwise.
+/// Sweeps compact buckets reaching it.
+pub const DEFAULT_COMPACTION_THRESHOLD: f32 = 0.5;
+
+/// How compaction renumbered the entries of a bucket.
+#[derive(Debug, Clone)]
+pub struct Compaction {
+    /// Number of entries before compaction.
+    pub entries: usize,
+    /// Previous numbers of the remaining entries, in their new order.
+    pub previous_ids: Vec<usize>,
+}
+
+/// Files rewritten by compaction are written with this suffix next to the ones they replace.
+const COMPACTION_SUFFIX: &str = ".compact";
+/// Lists the files rewritten by compaction once all of them are complete.
+const COMPACTION_MARKER: &str = "compaction.done";
+
+/// Keys a scan computes logits from.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub enum KeySource {
+    /// Keys as they were inserted, stored as the bucket's element type.
+    #[default]
+    Exact,
+    /// Int8 quantized copy of the keys, see [`KeyQuantization::Int8`].
+    Int8,
+    /// Product quantization codes of the keys, see [`Bucket::compress_pq`].
+    Pq,
 }
 
-pub struct Row {}
+/// Read-only mapping of the bucket files. Invalidated by every write to the bucket.
+struct BucketMapping {
+    keys: Mmap,
+    values: Mmap,
+    int8_keys: Option<Mmap>,
+    pq_codes: Option<Mmap>,
+}
+
+impl BucketMapping {
+    fn keys(&self, source: KeySource) -> Result<&Mmap, InvalidLayoutError> {
+        match source {
+            KeySource::Exact => Ok(&self.keys),
+            KeySource::Int8 => self.int8_keys.as_ref().ok_or(InvalidLayoutError),
+            KeySource::Pq => self.pq_codes.as_ref().ok_or(InvalidLayoutError),
+        }
+    }
+}
+
+/// Asks the OS to start reading the given byte range of a mapped file in background.
+fn advise_mapped_will_need(map: &Mmap, offset: usize, len: usize) {
+    #[cfg(unix)]
+    {
+        // Only a hint, scan works the same if it is not supported.
+        let _ = map.advise_range(memmap2::Advice::WillNeed, offset, len);
+    }
+    #[cfg(not(unix))]
+    let _ = (map, offset, len);
+}
 
 pub struct Bucket {
+    path: PathBuf,
     keys_handle: File,
     values_handle: File,
-    qkv_vec_size: u32,
+    /// Present if the bucket keeps int8 quantized keys.
+    int8_keys_handle: Option<File>,
+    /// Present if the bucket was compressed with a product quantizer.
+    pq_codes_handle: Option<File>,
+    pq: Option<ProductQuantizer>,
+    hnsw: Option<HnswIndex>,
+    ivf: Option<IvfIndex>,
+    timestamps_handle: File,
+    /// Insertion time of every entry in milliseconds since the Unix epoch.
+    timestamps: Vec<u64>,
+    metadata_handle: File,
+    metadata_offsets_handle: File,
+    /// Offset in `metadata.bin` where metadata of every entry ends, it starts where metadata of the previous one ends.
+    metadata_offsets: Vec<u64>,
+    tombstones_handle: File,
+    /// Whether every entry was deleted.
+    deleted: Vec<bool>,
+    /// Number of deleted entries.
+    deleted_count: usize,
+    external_ids_handle: File,
+    /// Entry every external id given by `UPSERT` was last written to.
+    external_ids: HashMap<String, usize>,
+    usage_handle: File,
+    /// How much scans attended to every entry.
+    usage: Vec<Usage>,
+    /// Whether scans recorded usage not written to `usage.bin` yet, see [`Bucket::flush_usage`].
+    usage_dirty: bool,
+    expirations_handle: File,
+    /// Time every entry expires at in milliseconds since the Unix epoch, `u64::MAX` if it never does.
+    expirations: Vec<u64>,
+    key_size: u32,
+    value_size: u32,
+    conf: BucketConfiguration,
+    mapping: Option<BucketMapping>,
 }
 
 impl Bucket {
-    pub async fn initialize(path: &Path, database_config: DatabaseConfiguration) -> Result<Bucket, std::io::Error> {
+    pub async fn initialize(path: &Path, database_config: DatabaseConfiguration, bucket_config: BucketConfiguration) -> Result<Bucket, StorageError> {
         tokio::fs::create_dir_all(path).await?;
+        let conf = serde_json::to_string_pretty(&bucket_config).map_err(std::io::Error::other)?;
+        tokio::fs::write(path.join("conf.json"), conf).await?;
+        let int8_keys_handle = match bucket_config.key_quantization {
+            KeyQuantization::None => None,
+            KeyQuantization::Int8 => Some(File::options().write(true).read(true).create(true).truncate(false).open(path.join("keys.i8")).await?),
+        };
+        let hnsw = bucket_config.hnsw.map(HnswIndex::new);
+        if let Some(index) = &hnsw {
+            tokio::fs::write(path.join("hnsw.l0"), []).await?;
+            write_hnsw_upper(path, index.upper()).await?;
+        }
         Ok(Self {
-            keys_handle: File::options().write(true).read(true).create(true).open(path.join("keys.bin")).await?,
-            values_handle: File::options().write(true).read(true).create(true).open(path.join("values.bin")).await?,
-            qkv_vec_size: database_config.qkv_vec_size,
+            path: path.into(),
+            keys_handle: File::options().write(true).read(true).create(true).truncate(false).open(path.join("keys.bin")).await?,
+            values_handle: File::options().write(true).read(true).create(true).truncate(false).open(path.join("values.bin")).await?,
+            int8_keys_handle,
+            pq_codes_handle: None,
+            pq: None,
+            hnsw,
+            ivf: None,
+            timestamps_handle: File::options().write(true).read(true).create(true).truncate(false).open(path.join("timestamps.bin")).await?,
+            timestamps: vec![],
+            metadata_handle: File::options().write(true).read(true).create(true).truncate(false).open(path.join("metadata.bin")).await?,
+            metadata_offsets_handle: File::options().write(true).read(true).create(true).truncate(false).open(path.join("metadata.offsets")).await?,
+            metadata_offsets: vec![],
+            tombstones_handle: File::options().write(true).read(true).create(true).truncate(false).open(path.join("tombstones.bin")).await?,
+            deleted: vec![],
+            deleted_count: 0,
+            external_ids_handle: File::options().write(true).read(true).create(true).truncate(false).open(path.join("external_ids.bin")).await?,
+            external_ids: HashMap::new(),
+            usage_handle: File::options().write(true).read(true).create(true).truncate(false).open(path.join("usage.bin")).await?,
+            usage: vec![],
+            usage_dirty: false,
+            expirations_handle: File::options().write(true).read(true).create(true).truncate(false).open(path.join("expirations.bin")).await?,
+            expirations: vec![],
+            key_size: database_config.key_size,
+            value_size: database_config.value_size(),
+            conf: bucket_config,
+            mapping: None,
         })
     }
 
-    pub async fn from_disk(path: &Path, database_config: &DatabaseConfiguration) -> Result<Bucket, std::io::Error> {
-        Ok(Self {
+    pub async fn from_disk(path: &Path, database_config: &DatabaseConfiguration) -> Result<Bucket, StorageError> {
+        recover_compaction(path).await?;
+        // Buckets created before `conf.json` was introduced store plain f32.
+        let corrupted = || StorageError::CorruptedConfiguration(path.display().to_string());
+        let conf_path = path.join("conf.json");
+        let conf: BucketConfiguration = if conf_path.exists() {
+            let content = tokio::fs::read_to_string(conf_path).await?;
+            serde_json::from_str(&content).map_err(|_| corrupted())?
+        } else {
+            BucketConfiguration::default()
+        };
+        let int8_keys_handle = match conf.key_quantization {
+            KeyQuantization::None => None,
+            KeyQuantization::Int8 => Some(File::options().write(true).read(true).open(path.join("keys.i8")).await?),
+        };
+        let (pq, pq_codes_handle) = match conf.pq {
+            None => (None, None),
+            Some(pq_conf) => {
+                let codebooks = tokio::fs::read(path.join("pq.codebooks")).await?;
+                let pq = ProductQuantizer::from_bytes(pq_conf, database_config.key_size as usize, &codebooks).ok_or_else(corrupted)?;
+                (Some(pq), Some(File::options().write(true).read(true).open(path.join("keys.pq")).await?))
+            }
+        };
+        let hnsw = match conf.hnsw {
+            None => None,
+            Some(hnsw_conf) => {
+                let level0 = tokio::fs::read(path.join("hnsw.l0")).await?;
+                let level0 = level0.chunks_exact(size_of::<u32>()).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect();
+                let upper = bincode::deserialize(&tokio::fs::read(path.join("hnsw.upper")).await?).map_err(|_| corrupted())?;
+                Some(HnswIndex::from_parts(hnsw_conf, level0, upper).ok_or_else(corrupted)?)
+            }
+        };
+        let ivf = match conf.ivf {
+            None => None,
+            Some(_) => {
+                let centroids = tokio::fs::read(path.join("ivf.centroids")).await?;
+                let assignments: Vec<u32> = tokio::fs::read(path.join("ivf.assignments")).await?
+                    .chunks_exact(size_of::<u32>())
+                    .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                    .collect();
+                Some(IvfIndex::from_parts(database_config.key_size as usize, &centroids, &assignments).ok_or_else(corrupted)?)
+            }
+        };
+        let mut bucket = Self {
+            path: path.into(),
             keys_handle: File::options().write(true).read(true).open(path.join("keys.bin")).await?,
             values_handle: File::options().write(true).read(true).open(path.join("values.bin")).await?,
-            qkv_vec_size: database_config.qkv_vec_size,
+            int8_keys_handle,
+            pq_codes_handle,
+            pq,
+            hnsw,
+            ivf,
+            // Buckets created before timestamps were recorded do not have the file yet.
+            timestamps_handle: File::options().write(true).read(true).create(true).truncate(false).open(path.join("timestamps.bin")).await?,
+            timestamps: tokio::fs::read(path.join("timestamps.bin")).await?
+                .chunks_exact(size_of::<u64>())
+                .map(|b| u64::from_le_bytes(b.try_into().expect("Chunks have the size of u64")))
+                .collect(),
+            // Same for metadata.
+            metadata_handle: File::options().write(true).read(true).create(true).truncate(false).open(path.join("metadata.bin")).await?,
+            metadata_offsets_handle: File::options().write(true).read(true).create(true).truncate(false).open(path.join("metadata.offsets")).await?,
+            metadata_offsets: tokio::fs::read(path.join("metadata.offsets")).await?
+                .chunks_exact(size_of::<u64>())
+                .map(|b| u64::from_le_bytes(b.try_into().expect("Chunks have the size of u64")))
+                .collect(),
+            // And for deletions, external ids, usage and expiration.
+            tombstones_handle: File::options().write(true).read(true).create(true).truncate(false).open(path.join("tombstones.bin")).await?,
+            deleted: vec![],
+            deleted_count: 0,
+            external_ids_handle: File::options().write(true).read(true).create(true).truncate(false).open(path.join("external_ids.bin")).await?,
+            external_ids: HashMap::new(),
+            usage_handle: File::options().write(true).read(true).create(true).truncate(false).open(path.join("usage.bin")).await?,
+            usage: tokio::fs::read(path.join("usage.bin")).await?.chunks_exact(Usage::SIZE).map(Usage::from_bytes).collect(),
+            usage_dirty: false,
+            expirations_handle: File::options().write(true).read(true).create(true).truncate(false).open(path.join("expirations.bin")).await?,
+            expirations: tokio::fs::read(path.join("expirations.bin")).await?
+                .chunks_exact(size_of::<u64>())
+                .map(|b| u64::from_le_bytes(b.try_into().expect("Chunks have the size of u64")))
+                .collect(),
+            key_size: database_config.key_size,
+            value_size: database_config.value_size(),
+            conf,
+            mapping: None,
+        };
+        // Entries written right before a crash may be missing from the indexes.
+        bucket.update_hnsw().await?;
+        bucket.update_ivf().await?;
+        bucket.update_timestamps().await?;
+        bucket.update_usage().await?;
+        bucket.update_expirations().await?;
+        bucket.update_metadata().await?;
+        bucket.load_tombstones().await?;
+        bucket.load_external_ids().await?;
+        Ok(bucket)
+    }
+
+    pub fn dtype(&self) -> DType {
+        self.conf.dtype
+    }
+
+    pub fn configuration(&self) -> &BucketConfiguration {
+        &self.conf
+    }
+
+    pub fn product_quantizer(&self) -> Option<&ProductQuantizer> {
+        self.pq.as_ref()
+    }
+
+    pub fn has_keys(&self, source: KeySource) -> bool {
+        match source {
+            KeySource::Exact => true,
+            KeySource::Int8 => self.int8_keys_handle.is_some(),
+            KeySource::Pq => self.pq_codes_handle.is_some(),
+        }
+    }
+
+    /// Size in bytes of a single key read from `source`.
+    pub fn key_row_size(&self, source: KeySource) -> usize {
+        match source {
+            KeySource::Exact => self.conf.dtype.size() * self.key_size as usize,
+            KeySource::Int8 => int8_row_size(self.key_size as usize),
+            KeySource::Pq => self.pq.as_ref().map_or(0, |pq| pq.configuration().subspaces),
+        }
+    }
+
+    /// Size in bytes of a single stored value.
+    pub fn value_row_size(&self) -> usize {
+        self.conf.dtype.size() * self.value_size as usize
+    }
+
+    fn key_handle(&self, source: KeySource) -> Result<&File, InvalidLayoutError> {
+        match source {
+            KeySource::Exact => Ok(&self.keys_handle),
+            KeySource::Int8 => self.int8_keys_handle.as_ref().ok_or(InvalidLayoutError),
+            KeySource::Pq => self.pq_codes_handle.as_ref().ok_or(InvalidLayoutError),
+        }
+    }
+
+    /// Makes sure `K` and `V` match element types keys from `source` and values are stored as.
+    /// Quantized keys are handed out as raw bytes.
+    fn check_elements<K, V>(&self, source: KeySource) -> Result<(), InvalidLayoutError> {
+        let key_size = match source {
+            KeySource::Exact => self.conf.dtype.size(),
+            KeySource::Int8 | KeySource::Pq => size_of::<u8>(),
+        };
+        if size_of::<K>() != key_size || size_of::<V>() != self.conf.dtype.size() {
+            return Err(InvalidLayoutError);
+        }
+        Ok(())
+    }
+
+    /// Reads the bucket batch by batch and calls `f` on every batch along with the index of its first entry.
+    /// Keys are read from `source`. Up to `prefetch_depth` batches are read ahead by a background reader while `f` is running.
+    pub async fn reduce_kv_batched<K, V, A: ?Sized, F: Fn(&mut A, usize, ArrayView2<K>, ArrayView2<V>)>(&mut self, source: KeySource, acc: &mut A, batch_size: usize, prefetch_depth: usize, f: F) -> Result<(), StorageError> {
+        self.check_elements::<K, V>(source)?;
+        let key_row_size = self.key_row_size(source);
+        let value_row_size = self.value_row_size();
+        let rows = self.rows().await?;
+        let batch_rows = batch_size.max(1);
+        let keys = self.key_handle(source)?.try_clone().await?.into_std().await;
+        let values = self.values_handle.try_clone().await?.into_std().await;
+
+        let (batches_tx, mut batches_rx) = tokio::sync::mpsc::channel(prefetch_depth.max(1));
+        // Buffers are passed back to the reader once the batch is processed, so the pipeline does not allocate.
+        let (free_tx, free_rx) = std::sync::mpsc::channel::<(Vec<u8>, Vec<u8>)>();
+        let reader = tokio::task::spawn_blocking(move || {
+            advise_sequential(&keys, (rows * key_row_size) as u64);
+            advise_sequential(&values, (rows * value_row_size) as u64);
+            let ahead = batch_rows * prefetch_depth;
+            let mut start = 0;
+            while start < rows {
+                let size = batch_rows.min(rows - start);
+                let end = start + size;
+                advise_will_need(&keys, (end * key_row_size) as u64, (ahead * key_row_size) as u64);
+                advise_will_need(&values, (end * value_row_size) as u64, (ahead * value_row_size) as u64);
+                let (mut keys_buf, mut values_buf) = free_rx.try_recv().unwrap_or_default();
+                keys_buf.resize(size * key_row_size, 0);
+                values_buf.resize(size * value_row_size, 0);
+                read_exact_at(&keys, &mut keys_buf, (start * key_row_size) as u64)?;
+                read_exact_at(&values, &mut values_buf, (start * value_row_size) as u64)?;
+                if batches_tx.blocking_send((start, keys_buf, values_buf)).is_err() {
+                    // Consumer stopped early, nothing to read for.
+                    break;
+                }
+                start = end;
+            }
+            Ok::<(), std::io::Error>(())
+        });
+
+        while let Some((start, keys_buf, values_buf)) = batches_rx.recv().await {
+            {
+                // Allows us to obtain &[T] from Vec<u8> without allocations
+                let keys: VecView<K> = VecView::from_vec(&keys_buf)?;
+                let values: VecView<V> = VecView::from_vec(&values_buf)?;
+                let (keys, values) = as_kv_views(&keys, key_row_size / size_of::<K>(), &values, value_row_size / size_of::<V>())?;
+                f(acc, start, keys, values);
+            }
+            // Reader may have already finished, in which case buffers are simply dropped.
+            let _ = free_tx.send((keys_buf, values_buf));
+        }
+        reader.await.map_err(std::io::Error::other)??;
+        Ok(())
+    }
+
+    /// Same as [`Bucket::reduce_kv_batched`], but reads batches directly from memory-mapped bucket files,
+    /// so neither the storage nor the kernel copies the data and repeated scans are served from the page cache.
+    /// Pages of the next `prefetch_depth` batches are requested from the OS before each batch is processed.
+    pub async fn reduce_kv_mapped<K, V, A: ?Sized, F: Fn(&mut A, usize, ArrayView2<K>, ArrayView2<V>)>(&mut self, source: KeySource, acc: &mut A, batch_size: usize, prefetch_depth: usize, f: F) -> Result<(), StorageError> {
+        self.check_elements::<K, V>(source)?;
+        let key_row_size = self.key_row_size(source);
+        let value_row_size = self.value_row_size();
+        let mapping = self.map()?;
+        let keys_map = mapping.keys(source)?;
+        let keys: VecView<K> = VecView::from_vec(keys_map)?;
+        let values: VecView<V> = VecView::from_vec(&mapping.values)?;
+        let (keys, values) = as_kv_views(&keys, key_row_size / size_of::<K>(), &values, value_row_size / size_of::<V>())?;
+
+        let rows = keys.nrows();
+        for start in (0..rows).step_by(batch_size.max(1)) {
+            let end = (start + batch_size).min(rows);
+            let prefetch_end = (end + batch_size * prefetch_depth).min(rows);
+            if prefetch_end > end {
+                advise_mapped_will_need(keys_map, end * key_row_size, (prefetch_end - end) * key_row_size);
+                advise_mapped_will_need(&mapping.values, end * value_row_size, (prefetch_end - end) * value_row_size);
+            }
+            f(acc, start, keys.slice(s![start..end, ..]), values.slice(s![start..end, ..]));
+        }
+        Ok(())
+    }
+
+    /// Reads exact keys of the given entries, converted to `f32`, one row per entry.
+    pub async fn read_keys<T: StoredElement>(&self, rows: &[usize]) -> Result<Array2<f32>, StorageError> {
+        self.read_rows::<T>(&self.keys_handle, self.key_size as usize, rows).await
+    }
+
+    /// Reads values of the given entries, converted to `f32`, one row per entry.
+    pub async fn read_values<T: StoredElement>(&self, rows: &[usize]) -> Result<Array2<f32>, StorageError> {
+        self.read_rows::<T>(&self.values_handle, self.value_size as usize, rows).await
+    }
+
+    /// Reads keys and values of the given entries with positioned reads, whatever the type of their elements.
+    pub async fn read_entries(&self, rows: &[usize]) -> Result<(Array2<f32>, Array2<f32>), StorageError> {
+        Ok(match self.conf.dtype {
+            DType::F32 => (self.read_keys::<f32>(rows).await?, self.read_values::<f32>(rows).await?),
+            DType::F16 => (self.read_keys::<half::f16>(rows).await?, self.read_values::<half::f16>(rows).await?),
+            DType::BF16 => (self.read_keys::<half::bf16>(rows).await?, self.read_values::<half::bf16>(rows).await?),
         })
     }
-    pub async fn reduce_kv_batched<A: ?Sized, F: Fn(&mut A, &[f32], &[f32]) -> ()>(&mut self, acc: &mut A, batch_size: usize, f: F) {
-        self.keys_handle.seek(SeekFrom::Start(0)).await.expect("I/O error occurred during bucket keys read.");
-        self.values_handle.seek(SeekFrom::Start(0)).await.expect("I/O error occurred during bucket values read.");
-
-        // Define buffers and load first block into memory
-        let mut keys_buf: Vec<u8> = Vec::with_capacity(size_of::<f32>() * self.qkv_vec_size as usize * batch_size);
-        let mut values_buf: Vec<u8> = Vec::with_capacity(size_of::<f32>() * self.qkv_vec_size as usize * batch_size);
-
-        // read_buf does NOT extend capacity, so after these reads buffers contain less or equal to self.qkv_vec_size * READ_BLOCK_SIZE floats.
-        self.keys_handle.read_buf(&mut keys_buf).await.expect("I/O error occurred during bucket values read.");
-        self.values_handle.read_buf(&mut values_buf).await.expect("I/O error occurred during bucket values read.");
-        loop {
-            // Allows us to obtain &[f32] from Vec<u8> without allocations
-            let keys: VecView<f32> = VecView::from_vec(&keys_buf).unwrap();
-            let values: VecView<f32> = VecView::from_vec(&values_buf).unwrap();
-            if keys.len() == 0 {
-                // Data file is ended.
-                break;
+
+    /// Reads rows of `row_len` elements of `file` at the given positions.
+    async fn read_rows<T: StoredElement>(&self, file: &File, row_len: usize, rows: &[usize]) -> Result<Array2<f32>, StorageError> {
+        self.check_elements::<T, T>(KeySource::Exact)?;
+        // The buffer of no rows is not aligned for `T`.
+        if rows.is_empty() {
+            return Ok(Array2::zeros((0, row_len)));
+        }
+        let row_size = self.conf.dtype.size() * row_len;
+        let file = file.try_clone().await?.into_std().await;
+        let positions = rows.to_vec();
+        let buf = tokio::task::spawn_blocking(move || {
+            let mut buf = vec![0u8; positions.len() * row_size];
+            for (chunk, row) in buf.chunks_exact_mut(row_size).zip(positions) {
+                read_exact_at(&file, chunk, (row * row_size) as u64)?;
+            }
+            Ok::<Vec<u8>, std::io::Error>(buf)
+        }).await.map_err(std::io::Error::other)??;
+        let data: VecView<T> = VecView::from_vec(&buf)?;
+        let data = ArrayView2::from_shape((rows.len(), row_len), &data).map_err(|_| InvalidLayoutError)?;
+        Ok(T::widen(data).into_owned())
+    }
+
+    pub fn has_hnsw(&self) -> bool {
+        self.hnsw.is_some()
+    }
+
+    /// Finds entries with approximately the `k` highest logits for every query using the HNSW graph,
+    /// considering `ef` candidates. Returns `None` if the bucket has no graph.
+    pub fn search_hnsw<T: StoredElement>(&mut self, q: &Array2<f32>, k: usize, ef: usize) -> Result<Option<Vec<Vec<usize>>>, StorageError> {
+        if self.hnsw.is_none() {
+            return Ok(None);
+        }
+        self.check_elements::<T, T>(KeySource::Exact)?;
+        self.map()?;
+        let keys = self.mapped_keys::<T>()?;
+        let index = self.hnsw.as_ref().expect("Presence of the graph is checked above");
+        Ok(Some(q.rows().into_iter().map(|q| index.search(&keys, &q.to_vec(), k, ef)).collect()))
+    }
+
+    /// Exact keys of the mapped bucket. Bucket must be mapped already.
+    fn mapped_keys<T: StoredElement>(&self) -> Result<MappedKeys<'_, T>, StorageError> {
+        let key_size = self.key_size as usize;
+        let mapping = self.mapping.as_ref().expect("Bucket is mapped by the caller");
+        let keys = VecView::<T>::from_vec(&mapping.keys)?.as_slice();
+        let keys = ArrayView2::from_shape((keys.len() / key_size, key_size), keys).map_err(|_| InvalidLayoutError)?;
+        Ok(MappedKeys { keys })
+    }
+
+    /// Adds entries missing from the HNSW graph and writes the changed parts of it.
+    async fn update_hnsw(&mut self) -> Result<(), StorageError> {
+        let Some(mut index) = self.hnsw.take() else {
+            return Ok(());
+        };
+        let extended = match self.conf.dtype {
+            DType::F32 => self.extend_hnsw::<f32>(&mut index),
+            DType::F16 => self.extend_hnsw::<half::f16>(&mut index),
+            DType::BF16 => self.extend_hnsw::<half::bf16>(&mut index),
+        };
+        let saved = match extended {
+            Ok(()) => self.save_hnsw(&mut index).await,
+            Err(err) => Err(err),
+        };
+        self.hnsw = Some(index);
+        saved
+    }
+
+    fn extend_hnsw<T: StoredElement>(&mut self, index: &mut HnswIndex) -> Result<(), StorageError> {
+        self.map()?;
+        let keys = self.mapped_keys::<T>()?;
+        if index.len() > keys.keys.nrows() {
+            return Err(StorageError::CorruptedConfiguration(self.path.display().to_string()));
+        }
+        while index.len() < keys.keys.nrows() {
+            index.insert(&keys);
+        }
+        Ok(())
+    }
+
+    /// Writes bottom layer slots changed since the last save, then the upper layers.
+    async fn save_hnsw(&mut self, index: &mut HnswIndex) -> Result<(), StorageError> {
+        let dirty = index.take_dirty();
+        if dirty.is_empty() {
+            return Ok(());
+        }
+        let mut level0 = File::options().write(true).open(self.path.join("hnsw.l0")).await?;
+        for node in dirty {
+            let slot: Vec<u8> = index.slot(node).iter().flat_map(|x| x.to_le_bytes()).collect();
+            level0.seek(SeekFrom::Start(node as u64 * index.slot_size() as u64)).await?;
+            level0.write_all(&slot).await?;
+        }
+        level0.flush().await?;
+        write_hnsw_upper(&self.path, index.upper()).await
+    }
+
+    /// Builds an HNSW graph over all entries of the bucket, replacing the previous one.
+    /// Entries inserted afterward are added on insert.
+    pub async fn build_hnsw(&mut self, hnsw_conf: HnswConfiguration) -> Result<(), StorageError> {
+        self.hnsw = None;
+        let index = HnswIndex::new(hnsw_conf);
+        tokio::fs::write(self.path.join("hnsw.l0"), []).await?;
+        write_hnsw_upper(&self.path, index.upper()).await?;
+        // An interrupted build continues when the bucket is loaded, as if the entries were just inserted.
+        self.write_configuration(BucketConfiguration { hnsw: Some(hnsw_conf), ..self.conf }).await?;
+        self.hnsw = Some(index);
+        self.update_hnsw().await
+    }
+
+    pub fn ivf(&self) -> Option<&IvfIndex> {
+        self.ivf.as_ref()
+    }
+
+    /// Assigns all entries of the bucket to lists of `index` and keeps it next to the keys, replacing the previous one.
+    /// Entries inserted afterward are assigned on insert.
+    pub async fn build_ivf(&mut self, mut index: IvfIndex) -> Result<(), StorageError> {
+        let mut assignments = Vec::new();
+        match self.conf.dtype {
+            DType::F32 => self.assign_ivf::<f32>(&index, &mut assignments).await?,
+            DType::F16 => self.assign_ivf::<half::f16>(&index, &mut assignments).await?,
+            DType::BF16 => self.assign_ivf::<half::bf16>(&index, &mut assignments).await?,
+        }
+        self.ivf = None;
+        let bytes: Vec<u8> = assignments.iter().flat_map(|x| x.to_le_bytes()).collect();
+        tokio::fs::write(self.path.join("ivf.assignments"), bytes).await?;
+        tokio::fs::write(self.path.join("ivf.centroids"), index.centroids_to_bytes()).await?;
+        // Configuration is written last, so the bucket never refers to an index that was not fully written.
+        self.write_configuration(BucketConfiguration { ivf: Some(index.configuration()), ..self.conf }).await?;
+        index.add(&assignments);
+        self.ivf = Some(index);
+        Ok(())
+    }
+
+    async fn assign_ivf<T: StoredElement>(&mut self, index: &IvfIndex, assignments: &mut Vec<u32>) -> Result<(), StorageError> {
+        const ASSIGN_BATCH_ROWS: usize = 4096;
+        self.reduce_kv_batched(KeySource::Exact, assignments, ASSIGN_BATCH_ROWS, 1, |assignments: &mut Vec<u32>, _: usize, k: ArrayView2<T>, _: ArrayView2<T>| {
+            assignments.extend(index.assign(T::widen(k).view()))
+        }).await
+    }
+
+    /// Insertion time of every entry in milliseconds since the Unix epoch.
+    pub fn timestamps(&self) -> &[u64] {
+        &self.timestamps
+    }
+
+    /// Records insertion time of entries missing from `timestamps.bin`, which were inserted before timestamps were recorded
+    /// or right before a crash. Such entries count as inserted when the values file was last modified.
+    async fn update_timestamps(&mut self) -> Result<(), StorageError> {
+        let rows = self.rows().await?;
+        if self.timestamps.len() >= rows {
+            self.timestamps.truncate(rows);
+            return Ok(());
+        }
+        let modified = self.values_handle.metadata().await?.modified()?;
+        self.append_timestamps(rows - self.timestamps.len(), unix_millis(modified)).await?;
+        Ok(())
+    }
+
+    /// Gives entries missing from `expirations.bin`, which were inserted before expiration was recorded or right before a crash,
+    /// the time to live of the bucket.
+    async fn update_expirations(&mut self) -> Result<(), std::io::Error> {
+        let rows = self.rows().await?;
+        if self.expirations.len() >= rows {
+            self.expirations.truncate(rows);
+            return Ok(());
+        }
+        self.append_expirations(rows - self.expirations.len(), self.conf.ttl_seconds).await
+    }
+
+    /// Appends expiration of the next `count` entries, whose timestamps are already appended, living for `ttl` seconds if given.
+    async fn append_expirations(&mut self, count: usize, ttl: Option<f32>) -> Result<(), std::io::Error> {
+        let start = self.expirations.len();
+        let ttl = ttl.map(|ttl| Duration::from_secs_f32(ttl).as_millis() as u64);
+        self.expirations.extend(self.timestamps[start..start + count].iter().map(|&timestamp| {
+            ttl.map_or(u64::MAX, |ttl| timestamp.saturating_add(ttl))
+        }));
+        let bytes: Vec<u8> = self.expirations[start..].iter().flat_map(|t| t.to_le_bytes()).collect();
+        self.expirations_handle.seek(SeekFrom::Start((start * size_of::<u64>()) as u64)).await?;
+        self.expirations_handle.write_all(&bytes).await?;
+        self.expirations_handle.flush().await
+    }
+
+    /// Whether entry `row` expired by `now`, given in milliseconds since the Unix epoch.
+    fn expired(&self, row: usize, now: u64) -> bool {
+        self.expirations.get(row).is_some_and(|&expiration| expiration <= now)
+    }
+
+    pub fn is_expired(&self, row: usize) -> bool {
+        self.expired(row, unix_millis(SystemTime::now()))
+    }
+
+    /// Number of entries that expired but were not deleted yet.
+    pub fn expired_entries(&self) -> usize {
+        let now = unix_millis(SystemTime::now());
+        (0..self.deleted.len()).filter(|&row| !self.deleted[row] && self.expired(row, now)).count()
+    }
+
+    /// Deletes expired entries, which scans already skip, so that compaction removes them once they reach the compaction threshold.
+    /// Returns the number of deleted entries. Unlike compaction, it keeps entry numbers.
+    pub async fn expire(&mut self) -> Result<usize, StorageError> {
+        let now = unix_millis(SystemTime::now());
+        let rows: Vec<usize> = (0..self.deleted.len()).filter(|&row| !self.deleted[row] && self.expired(row, now)).collect();
+        self.delete(&rows).await
+    }
+
+    /// Gives entries missing from `usage.bin`, which were inserted before usage was recorded or right before a crash,
+    /// no attention weight and their insertion time as the time they were last used.
+    async fn update_usage(&mut self) -> Result<(), std::io::Error> {
+        let rows = self.rows().await?;
+        if self.usage.len() >= rows {
+            self.usage.truncate(rows);
+            return Ok(());
+        }
+        self.append_usage(rows - self.usage.len()).await
+    }
+
+    /// Appends usage of the next `count` entries, whose timestamps are already appended.
+    async fn append_usage(&mut self, count: usize) -> Result<(), std::io::Error> {
+        let start = self.usage.len();
+        self.usage.extend(self.timestamps[start..start + count].iter().map(|&last_used| Usage { last_used, weight: 0. }));
+        let bytes: Vec<u8> = self.usage[start..].iter().flat_map(|usage| usage.to_bytes()).collect();
+        self.usage_handle.seek(SeekFrom::Start((start * Usage::SIZE) as u64)).await?;
+        self.usage_handle.write_all(&bytes).await?;
+        self.usage_handle.flush().await
+    }
+
+    /// Adds attention weight received by entries in a scan, given as `(row, weight)` for every query and head, to their usage.
+    /// Usage is kept in memory until [`Bucket::flush_usage`] writes it.
+    pub fn record_usage(&mut self, received: &[(usize, f32)]) {
+        let now = unix_millis(SystemTime::now());
+        for &(row, weight) in received {
+            let usage = &mut self.usage[row];
+            usage.weight += weight;
+            if weight >= LRU_USE_WEIGHT {
+                usage.last_used = now;
+            }
+        }
+        self.usage_dirty |= !received.is_empty();
+    }
+
+    /// Writes usage recorded by scans since the last flush to `usage.bin`, rewriting the whole file at once.
+    pub async fn flush_usage(&mut self) -> Result<(), std::io::Error> {
+        if !self.usage_dirty {
+            return Ok(());
+        }
+        let bytes: Vec<u8> = self.usage.iter().flat_map(|usage| usage.to_bytes()).collect();
+        self.usage_handle.seek(SeekFrom::Start(0)).await?;
+        self.usage_handle.write_all(&bytes).await?;
+        self.usage_handle.flush().await?;
+        self.usage_dirty = false;
+        Ok(())
+    }
+
+    /// Deletes entries the bucket holds beyond `max_entries`, expired ones first and then those chosen by its eviction policy.
+    /// Entries from `inserted` on were just inserted and are evicted only if they do not fit into the bucket on their own,
+    /// oldest first. Returns the number of evicted entries that had not expired.
+    pub async fn evict(&mut self, inserted: usize) -> Result<usize, StorageError> {
+        let Some(max_entries) = self.conf.max_entries else {
+            return Ok(0);
+        };
+        let mut entries = self.deleted.len() - self.deleted_count;
+        if entries <= max_entries {
+            return Ok(0);
+        }
+        entries -= self.expire().await?;
+        if entries <= max_entries {
+            return Ok(0);
+        }
+        let mut candidates: Vec<usize> = (0..inserted).filter(|&row| !self.deleted[row]).collect();
+        match self.conf.eviction {
+            Eviction::Fifo => {}
+            // Sorting is stable, so older entries go first among equally used ones.
+            Eviction::Lru => candidates.sort_by_key(|&row| self.usage[row].last_used),
+            Eviction::Lfu => candidates.sort_by(|&a, &b| self.usage[a].weight.total_cmp(&self.usage[b].weight)),
+        }
+        candidates.extend((inserted..self.deleted.len()).filter(|&row| !self.deleted[row]));
+        candidates.truncate(entries - max_entries);
+        self.delete(&candidates).await
+    }
+
+    /// Appends insertion time `timestamp` of the next `count` entries.
+    async fn append_timestamps(&mut self, count: usize, timestamp: u64) -> Result<(), std::io::Error> {
+        let start = self.timestamps.len();
+        self.timestamps.resize(start + count, timestamp);
+        // Written at the position of the first new entry, so timestamps of a crashed insert are overwritten.
+        let bytes: Vec<u8> = self.timestamps[start..].iter().flat_map(|t| t.to_le_bytes()).collect();
+        self.timestamps_handle.seek(SeekFrom::Start((start * size_of::<u64>()) as u64)).await?;
+        self.timestamps_handle.write_all(&bytes).await?;
+        self.timestamps_handle.flush().await
+    }
+
+    /// Whether any entry of the bucket has metadata.
+    pub fn has_metadata(&self) -> bool {
+        self.metadata_bytes() > 0
+    }
+
+    /// Size of metadata of all entries in `metadata.bin`.
+    pub fn metadata_bytes(&self) -> u64 {
+        self.metadata_offsets.last().copied().unwrap_or(0)
+    }
+
+    /// Reads metadata of the given entries, entries inserted without it having no fields.
+    pub async fn read_metadata(&self, rows: &[usize]) -> Result<Vec<Metadata>, StorageError> {
+        let ranges: Vec<(u64, u64)> = rows.iter().map(|&row| {
+            let start = if row == 0 { 0 } else { self.metadata_offsets[row - 1] };
+            (start, self.metadata_offsets[row])
+        }).collect();
+        let file = self.metadata_handle.try_clone().await?.into_std().await;
+        let records = tokio::task::spawn_blocking(move || {
+            ranges.into_iter().map(|(start, end)| {
+                let mut buf = vec![0u8; (end - start) as usize];
+                read_exact_at(&file, &mut buf, start)?;
+                Ok(buf)
+            }).collect::<Result<Vec<Vec<u8>>, std::io::Error>>()
+        }).await.map_err(std::io::Error::other)??;
+        Ok(records.iter().map(|bytes| Metadata::from_bytes(bytes).ok_or(InvalidLayoutError)).collect::<Result<_, _>>()?)
+    }
+
+    /// Reads metadata of all entries as stored in `metadata.bin`.
+    async fn read_all_metadata(&self) -> Result<Vec<u8>, std::io::Error> {
+        let mut bytes = vec![0u8; self.metadata_bytes() as usize];
+        let file = self.metadata_handle.try_clone().await?.into_std().await;
+        tokio::task::spawn_blocking(move || read_exact_at(&file, &mut bytes, 0).map(|_| bytes)).await.map_err(std::io::Error::other)?
+    }
+
+    /// Tells for every entry whether it matches `filter`, reading metadata of all entries at once.
+    /// Deleted and expired entries are never matched.
+    pub async fn filter(&self, filter: &Filter) -> Result<Vec<bool>, StorageError> {
+        let now = unix_millis(SystemTime::now());
+        let bytes = self.read_all_metadata().await?;
+        let mut start = 0;
+        let mut matches = Vec::with_capacity(self.metadata_offsets.len());
+        for (row, &end) in self.metadata_offsets.iter().enumerate() {
+            let metadata = Metadata::from_bytes(&bytes[start..end as usize]).ok_or(InvalidLayoutError)?;
+            matches.push(!self.deleted[row] && !self.expired(row, now) && filter.matches(row, &metadata));
+            start = end as usize;
+        }
+        Ok(matches)
+    }
+
+    /// Tells for every entry whether scans attend to it: it was not deleted, did not expire and matches `filter`, if one is given.
+    /// None if scans attend to all entries.
+    pub async fn mask(&self, filter: Option<&Filter>) -> Result<Option<Vec<bool>>, StorageError> {
+        let now = unix_millis(SystemTime::now());
+        match filter {
+            Some(filter) => Ok(Some(self.filter(filter).await?)),
+            None if self.deleted_count > 0 || self.expirations.iter().any(|&expiration| expiration <= now) => {
+                Ok(Some((0..self.deleted.len()).map(|row| !self.deleted[row] && !self.expired(row, now)).collect()))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Number of deleted entries still stored in the bucket.
+    pub fn deleted_entries(&self) -> usize {
+        self.deleted_count
+    }
+
+    pub fn is_deleted(&self, row: usize) -> bool {
+        self.deleted.get(row).is_some_and(|&deleted| deleted)
+    }
+
+    /// Marks the given entries as deleted, returning how many of them were not deleted before.
+    /// Scans skip deleted entries until compaction removes them, their external ids are free right away.
+    pub async fn delete(&mut self, rows: &[usize]) -> Result<usize, StorageError> {
+        let mut rows: Vec<usize> = rows.iter().copied().filter(|&row| row < self.deleted.len() && !self.deleted[row]).collect();
+        rows.sort_unstable();
+        rows.dedup();
+        let bytes: Vec<u8> = rows.iter().flat_map(|&row| (row as u64).to_le_bytes()).collect();
+        self.tombstones_handle.seek(SeekFrom::End(0)).await?;
+        self.tombstones_handle.write_all(&bytes).await?;
+        self.tombstones_handle.flush().await?;
+        for &row in &rows {
+            self.deleted[row] = true;
+        }
+        self.deleted_count += rows.len();
+        if !rows.is_empty() {
+            let deleted = &self.deleted;
+            self.external_ids.retain(|_, row| !deleted[*row]);
+        }
+        Ok(rows.len())
+    }
+
+    /// Reads entries deleted since the last compaction from `tombstones.bin`.
+    async fn load_tombstones(&mut self) -> Result<(), StorageError> {
+        let rows = self.rows().await?;
+        self.deleted = vec![false; rows];
+        self.deleted_count = 0;
+        let tombstones = tokio::fs::read(self.path.join("tombstones.bin")).await?;
+        for row in tombstones.chunks_exact(size_of::<u64>()).map(|b| u64::from_le_bytes(b.try_into().expect("Chunks have the size of u64")) as usize) {
+            // Entries deleted right before a crash may have never been fully written.
+            if row < rows && !self.deleted[row] {
+                self.deleted[row] = true;
+                self.deleted_count += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Replays `external_ids.bin`, forgetting external ids of entries that were deleted or never fully written.
+    /// A record cut short by a crash is overwritten by the next one.
+    async fn load_external_ids(&mut self) -> Result<(), StorageError> {
+        let bytes = tokio::fs::read(self.path.join("external_ids.bin")).await?;
+        let mut reader = bytes.as_slice();
+        let mut map = HashMap::new();
+        let mut len = 0;
+        while let Ok((row, id)) = bincode::deserialize_from::<_, (u64, String)>(&mut reader) {
+            map.insert(id, row as usize);
+            len = bytes.len() - reader.len();
+        }
+        self.external_ids_handle.set_len(len as u64).await?;
+        self.external_ids_handle.seek(SeekFrom::End(0)).await?;
+        map.retain(|_, row| self.deleted.get(*row).is_some_and(|deleted| !deleted));
+        self.external_ids = map;
+        Ok(())
+    }
+
+    /// Writes entries given by `UPSERT`, replacing the entries previously written with the same external ids,
+    /// which are deleted. Returns the number of replaced entries.
+    pub async fn upsert_kv(&mut self, data: Vec<(Vec<f32>, Vec<f32>)>, metadata: Option<Vec<Metadata>>, ids: Vec<String>, ttl: Option<f32>) -> Result<usize, StorageError> {
+        let start = self.rows().await?;
+        self.insert_kv(data, metadata, ttl).await?;
+        // Entries are inserted before their ids are recorded, so a crash leaves the old entries in place rather than losing them.
+        let mut replaced = vec![];
+        let mut bytes = vec![];
+        for (row, id) in (start..).zip(ids) {
+            bytes.extend(bincode::serialize(&(row as u64, &id)).map_err(std::io::Error::other)?);
+            if let Some(previous) = self.external_ids.insert(id, row) {
+                replaced.push(previous);
+            }
+        }
+        self.external_ids_handle.write_all(&bytes).await?;
+        self.external_ids_handle.flush().await?;
+        self.delete(&replaced).await
+    }
+
+    /// Overwrites values of the given entries with `value`.
+    pub async fn update_values(&mut self, rows: &[usize], value: Vec<f32>) -> Result<(), StorageError> {
+        match self.conf.dtype {
+            DType::F32 => self.update_values_as::<f32>(rows, value).await?,
+            DType::F16 => self.update_values_as::<half::f16>(rows, value).await?,
+            DType::BF16 => self.update_values_as::<half::bf16>(rows, value).await?,
+        }
+        Ok(())
+    }
+
+    async fn update_values_as<T: StoredElement>(&mut self, rows: &[usize], value: Vec<f32>) -> Result<(), std::io::Error> {
+        self.mapping = None;
+        let value: Vec<T> = value.into_iter().map(T::from_f32).collect();
+        let bytes = unsafe { std::slice::from_raw_parts(value.as_ptr() as *const u8, value.len() * size_of::<T>()) };
+        for &row in rows {
+            self.values_handle.seek(SeekFrom::Start((row * self.value_row_size()) as u64)).await?;
+            self.values_handle.write_all(bytes).await?;
+        }
+        self.values_handle.flush().await
+    }
+
+    /// Whether deleted entries make up at least the compaction threshold of the bucket.
+    pub fn needs_compaction(&self) -> bool {
+        self.deleted_count > 0 && self.deleted_count as f32 >= self.conf.compaction_threshold() * self.deleted.len() as f32
+    }
+
+    /// Rewrites the files of the bucket without deleted and expired entries, renumbering the remaining ones, and rebuilds its HNSW graph.
+    /// New files are written next to the old ones and replace them only once all of them are complete,
+    /// so a compaction interrupted by a crash is either finished or discarded when the bucket is loaded.
+    /// The bucket must be loaded again afterward. Returns how the remaining entries were renumbered.
+    pub async fn compact(&mut self) -> Result<Compaction, StorageError> {
+        let (names, compaction) = self.write_compaction().await?;
+        // Once the marker is written, the compaction is finished even if the process crashes.
+        write_compaction_marker(&self.path, &names).await?;
+        recover_compaction(&self.path).await?;
+        Ok(compaction)
+    }
+
+    /// Writes the files of the compacted bucket next to the old ones, returning their names and the renumbering.
+    async fn write_compaction(&mut self) -> Result<(Vec<&'static str>, Compaction), StorageError> {
+        self.expire().await?;
+        // Rows of `usage.bin` are copied, so usage kept in memory has to reach it first.
+        self.flush_usage().await?;
+        self.mapping = None;
+        let keep: Arc<[bool]> = self.deleted.iter().map(|deleted| !deleted).collect();
+        let mut files = vec![
+            ("keys.bin", self.key_row_size(KeySource::Exact)),
+            ("values.bin", self.value_row_size()),
+            ("timestamps.bin", size_of::<u64>()),
+            ("usage.bin", Usage::SIZE),
+            ("expirations.bin", size_of::<u64>()),
+        ];
+        if self.has_keys(KeySource::Int8) {
+            files.push(("keys.i8", self.key_row_size(KeySource::Int8)));
+        }
+        if self.has_keys(KeySource::Pq) {
+            files.push(("keys.pq", self.key_row_size(KeySource::Pq)));
+        }
+        if self.ivf.is_some() {
+            files.push(("ivf.assignments", size_of::<u32>()));
+        }
+        for &(name, row_size) in &files {
+            let (from, to) = (self.path.join(name), compaction_path(&self.path, name));
+            let keep = keep.clone();
+            tokio::task::spawn_blocking(move || copy_kept_rows(&from, &to, row_size, &keep)).await.map_err(std::io::Error::other)??;
+        }
+        let mut names: Vec<&'static str> = files.iter().map(|&(name, _)| name).collect();
+
+        let metadata = self.read_all_metadata().await?;
+        let (mut kept_metadata, mut kept_offsets) = (vec![], vec![]);
+        let mut start = 0;
+        for (row, &end) in self.metadata_offsets.iter().enumerate() {
+            if keep[row] {
+                kept_metadata.extend_from_slice(&metadata[start as usize..end as usize]);
+                kept_offsets.extend((kept_metadata.len() as u64).to_le_bytes());
             }
+            start = end;
+        }
+        // Numbers of the remaining entries are shifted down by the number of deleted entries before them.
+        let mut renumbered = vec![0; keep.len()];
+        let mut kept = 0;
+        for (row, &keep) in keep.iter().enumerate() {
+            renumbered[row] = kept;
+            kept += keep as usize;
+        }
+        let mut kept_external_ids = vec![];
+        for (id, &row) in self.external_ids.iter().filter(|&(_, &row)| keep[row]) {
+            kept_external_ids.extend(bincode::serialize(&(renumbered[row] as u64, id)).map_err(std::io::Error::other)?);
+        }
+        let mut written = vec![
+            ("metadata.bin", kept_metadata),
+            ("metadata.offsets", kept_offsets),
+            ("tombstones.bin", vec![]),
+            ("external_ids.bin", kept_external_ids),
+        ];
+        if let Some(hnsw_conf) = self.conf.hnsw {
+            // Neighbours refer to entries by their numbers, so the graph is built again once the bucket is loaded.
+            let upper = bincode::serialize(HnswIndex::new(hnsw_conf).upper()).map_err(std::io::Error::other)?;
+            written.extend([("hnsw.l0", vec![]), ("hnsw.upper", upper)]);
+        }
+        for (name, bytes) in written {
+            let path = compaction_path(&self.path, name);
+            tokio::task::spawn_blocking(move || write_synced(&path, &bytes)).await.map_err(std::io::Error::other)??;
+            names.push(name);
+        }
+        let previous_ids = keep.iter().enumerate().filter(|&(_, &keep)| keep).map(|(row, _)| row).collect();
+        Ok((names, Compaction { entries: keep.len(), previous_ids }))
+    }
+
+    /// Gives no metadata to entries missing from `metadata.offsets`, which were inserted before metadata was stored
+    /// or right before a crash, and forgets offsets of entries that were never fully written.
+    async fn update_metadata(&mut self) -> Result<(), StorageError> {
+        let rows = self.rows().await?;
+        if self.metadata_offsets.len() >= rows {
+            self.metadata_offsets.truncate(rows);
+            return Ok(());
+        }
+        let missing = rows - self.metadata_offsets.len();
+        self.append_metadata(vec![Metadata::default(); missing]).await?;
+        Ok(())
+    }
 
-            f(acc, keys.as_ref(), values.as_ref());
+    /// Writes metadata of new entries after metadata of the existing ones, then their offsets.
+    async fn append_metadata(&mut self, metadata: Vec<Metadata>) -> Result<(), std::io::Error> {
+        let start = self.metadata_offsets.len();
+        let offset = self.metadata_offsets.last().copied().unwrap_or(0);
+        let mut bytes = vec![];
+        for entry in metadata {
+            bytes.extend(entry.to_bytes());
+            self.metadata_offsets.push(offset + bytes.len() as u64);
+        }
+        // Both are written at the position of the first new entry, so metadata of a crashed insert is overwritten.
+        self.metadata_handle.seek(SeekFrom::Start(offset)).await?;
+        self.metadata_handle.write_all(&bytes).await?;
+        self.metadata_handle.flush().await?;
+        let offsets: Vec<u8> = self.metadata_offsets[start..].iter().flat_map(|o| o.to_le_bytes()).collect();
+        self.metadata_offsets_handle.seek(SeekFrom::Start((start * size_of::<u64>()) as u64)).await?;
+        self.metadata_offsets_handle.write_all(&offsets).await?;
+        self.metadata_offsets_handle.flush().await
+    }
+
+    /// Assigns entries missing from the inverted file index and writes their lists.
+    async fn update_ivf(&mut self) -> Result<(), StorageError> {
+        let Some(index) = &self.ivf else {
+            return Ok(());
+        };
+        let start = index.len();
+        let rows = self.rows().await?;
+        if start > rows {
+            return Err(StorageError::CorruptedConfiguration(self.path.display().to_string()));
+        }
+        if start == rows {
+            return Ok(());
+        }
+        let new_rows: Vec<usize> = (start..rows).collect();
+        let keys = match self.conf.dtype {
+            DType::F32 => self.read_keys::<f32>(&new_rows).await?,
+            DType::F16 => self.read_keys::<half::f16>(&new_rows).await?,
+            DType::BF16 => self.read_keys::<half::bf16>(&new_rows).await?,
+        };
+        let assignments = index.assign(keys.view());
+        // Written at the position of the first new entry, so a partially written list of a crashed insert is overwritten.
+        let bytes: Vec<u8> = assignments.iter().flat_map(|x| x.to_le_bytes()).collect();
+        let mut file = File::options().write(true).open(self.path.join("ivf.assignments")).await?;
+        file.seek(SeekFrom::Start((start * size_of::<u32>()) as u64)).await?;
+        file.write_all(&bytes).await?;
+        file.flush().await?;
+        self.ivf.as_mut().expect("Presence of the index is checked above").add(&assignments);
+        Ok(())
+    }
 
-            // Required because read_buf adds to existing buffer instead of rewriting from scratch.
-            keys_buf.clear();
-            values_buf.clear();
+    /// Replaces `conf.json` of the bucket with `conf`.
+    async fn write_configuration(&mut self, conf: BucketConfiguration) -> Result<(), StorageError> {
+        let conf_json = serde_json::to_string_pretty(&conf).map_err(std::io::Error::other)?;
+        tokio::fs::write(self.path.join("conf.json"), conf_json).await?;
+        self.conf = conf;
+        Ok(())
+    }
 
-            self.keys_handle.read_buf(&mut keys_buf).await.expect("I/O error occurred during bucket values read.");
-            self.values_handle.read_buf(&mut values_buf).await.expect("I/O error occurred during bucket values read.");
+    /// Reads exact keys of up to `limit` entries spread evenly over the bucket.
+    pub async fn sample_keys(&self, limit: usize) -> Result<Array2<f32>, StorageError> {
+        let rows = self.rows().await?;
+        let step = rows.div_ceil(limit.max(1)).max(1);
+        let sample: Vec<usize> = (0..rows).step_by(step).collect();
+        match self.conf.dtype {
+            DType::F32 => self.read_keys::<f32>(&sample).await,
+            DType::F16 => self.read_keys::<half::f16>(&sample).await,
+            DType::BF16 => self.read_keys::<half::bf16>(&sample).await,
         }
     }
 
-    pub async fn insert_kv(&mut self, data: Vec<(Vec<f32>, Vec<f32>)>) -> Result<(), std::io::Error> {
-        let mut keys_to_be_written = Vec::with_capacity(data.len() * self.qkv_vec_size as usize);
-        let mut values_to_be_written = Vec::with_capacity(data.len() * self.qkv_vec_size as usize);
-        for (mut k, mut v) in data.into_iter() {
-            keys_to_be_written.append(&mut k);
-            values_to_be_written.append(&mut v);
+    /// Encodes all keys with `pq` and keeps the codes next to the exact keys, replacing any previous ones.
+    /// Entries inserted afterward are encoded on insert.
+    pub async fn compress_pq(&mut self, pq: ProductQuantizer) -> Result<(), StorageError> {
+        let mut codes = Vec::new();
+        match self.conf.dtype {
+            DType::F32 => self.encode_pq::<f32>(&pq, &mut codes).await?,
+            DType::F16 => self.encode_pq::<half::f16>(&pq, &mut codes).await?,
+            DType::BF16 => self.encode_pq::<half::bf16>(&pq, &mut codes).await?,
         }
-        self.keys_handle.seek(SeekFrom::End(0)).await.expect("I/O error occurred during bucket values read.");
-        self.values_handle.seek(SeekFrom::End(0)).await.expect("I/O error occurred during bucket values read.");
-        let keys_bytes = unsafe { std::slice::from_raw_parts(keys_to_be_written.as_ptr() as *const u8, keys_to_be_written.len() * size_of::<f32>()) };
-        let values_bytes = unsafe { std::slice::from_raw_parts(values_to_be_written.as_ptr() as *const u8, values_to_be_written.len() * size_of::<f32>()) };
-        self.keys_handle.write(keys_bytes).await?;
-        self.values_handle.write(values_bytes).await?;
+        self.mapping = None;
+        self.pq_codes_handle = None;
+        tokio::fs::write(self.path.join("keys.pq"), codes).await?;
+        tokio::fs::write(self.path.join("pq.codebooks"), pq.to_bytes()).await?;
+        // Configuration is written last, so the bucket never refers to codes that were not fully written.
+        self.write_configuration(BucketConfiguration { pq: Some(pq.configuration()), ..self.conf }).await?;
+        self.pq_codes_handle = Some(File::options().write(true).read(true).open(self.path.join("keys.pq")).await?);
+        self.pq = Some(pq);
+        Ok(())
+    }
+
+    async fn encode_pq<T: StoredElement>(&mut self, pq: &ProductQuantizer, codes: &mut Vec<u8>) -> Result<(), StorageError> {
+        const ENCODE_BATCH_ROWS: usize = 4096;
+        self.reduce_kv_batched(KeySource::Exact, codes, ENCODE_BATCH_ROWS, 1, |codes: &mut Vec<u8>, _: usize, k: ArrayView2<T>, _: ArrayView2<T>| {
+            pq.encode(T::widen(k).view(), codes)
+        }).await
+    }
+
+    /// Returns mapping of the bucket files, creating it if bucket was modified since the last scan.
+    fn map(&mut self) -> Result<&BucketMapping, StorageError> {
+        if self.mapping.is_none() {
+            // Safety: bucket files are only modified through `&mut self`, which also drops the mapping.
+            let keys = unsafe { Mmap::map(&self.keys_handle)? };
+            let values = unsafe { Mmap::map(&self.values_handle)? };
+            let int8_keys = match &self.int8_keys_handle {
+                None => None,
+                Some(handle) => Some(unsafe { Mmap::map(handle)? }),
+            };
+            let pq_codes = match &self.pq_codes_handle {
+                None => None,
+                Some(handle) => Some(unsafe { Mmap::map(handle)? }),
+            };
+            #[cfg(unix)]
+            {
+                // Only a hint, scan works the same if it is not supported.
+                for map in [Some(&keys), Some(&values), int8_keys.as_ref(), pq_codes.as_ref()].into_iter().flatten() {
+                    let _ = map.advise(memmap2::Advice::Sequential);
+                }
+            }
+            self.mapping = Some(BucketMapping { keys, values, int8_keys, pq_codes });
+        }
+        Ok(self.mapping.as_ref().expect("Mapping is initialized above"))
+    }
+
+    /// Number of entries stored in the bucket.
+    pub async fn rows(&self) -> Result<usize, std::io::Error> {
+        let len = self.values_handle.metadata().await?.len() as usize;
+        Ok(len / self.value_row_size())
+    }
+
+    /// Appends entries along with their metadata, if given it has one element per entry. Entries live for `ttl` seconds
+    /// if given, for the time to live of the bucket otherwise.
+    pub async fn insert_kv(&mut self, data: Vec<(Vec<f32>, Vec<f32>)>, metadata: Option<Vec<Metadata>>, ttl: Option<f32>) -> Result<(), StorageError> {
+        let count = data.len();
+        match self.conf.dtype {
+            DType::F32 => self.insert_kv_as::<f32>(data).await?,
+            DType::F16 => self.insert_kv_as::<half::f16>(data).await?,
+            DType::BF16 => self.insert_kv_as::<half::bf16>(data).await?,
+        }
+        self.append_timestamps(count, unix_millis(SystemTime::now())).await?;
+        self.append_usage(count).await?;
+        self.append_expirations(count, ttl.or(self.conf.ttl_seconds)).await?;
+        self.append_metadata(metadata.unwrap_or_else(|| vec![Metadata::default(); count])).await?;
+        self.deleted.resize(self.deleted.len() + count, false);
+        self.update_hnsw().await?;
+        self.update_ivf().await
+    }
+
+    /// Appends entries converting them to the element type `T` the bucket is stored as.
+    async fn insert_kv_as<T: StoredElement>(&mut self, data: Vec<(Vec<f32>, Vec<f32>)>) -> Result<(), std::io::Error> {
+        self.mapping = None;
+        let mut keys_to_be_written: Vec<T> = Vec::with_capacity(data.len() * self.key_size as usize);
+        let mut values_to_be_written: Vec<T> = Vec::with_capacity(data.len() * self.value_size as usize);
+        let mut int8_keys_to_be_written: Vec<u8> = Vec::new();
+        let mut pq_codes_to_be_written: Vec<u8> = Vec::new();
+        if let Some(pq) = &self.pq {
+            let keys: Vec<f32> = data.iter().flat_map(|(k, _)| k.iter().copied()).collect();
+            let keys = ArrayView2::from_shape((data.len(), self.key_size as usize), &keys).map_err(std::io::Error::other)?;
+            pq.encode(keys, &mut pq_codes_to_be_written);
+        }
+        for (k, v) in data.into_iter() {
+            if self.int8_keys_handle.is_some() {
+                quantize_int8(&k, &mut int8_keys_to_be_written);
+            }
+            keys_to_be_written.extend(k.into_iter().map(T::from_f32));
+            values_to_be_written.extend(v.into_iter().map(T::from_f32));
+        }
+        self.keys_handle.seek(SeekFrom::End(0)).await?;
+        self.values_handle.seek(SeekFrom::End(0)).await?;
+        let keys_bytes = unsafe { std::slice::from_raw_parts(keys_to_be_written.as_ptr() as *const u8, keys_to_be_written.len() * size_of::<T>()) };
+        let values_bytes = unsafe { std::slice::from_raw_parts(values_to_be_written.as_ptr() as *const u8, values_to_be_written.len() * size_of::<T>()) };
+        self.keys_handle.write_all(keys_bytes).await?;
+        self.values_handle.write_all(values_bytes).await?;
         self.keys_handle.flush().await?;
         self.values_handle.flush().await?;
+        if let Some(handle) = &mut self.int8_keys_handle {
+            handle.seek(SeekFrom::End(0)).await?;
+            handle.write_all(&int8_keys_to_be_written).await?;
+            handle.flush().await?;
+        }
+        if let Some(handle) = &mut self.pq_codes_handle {
+            handle.seek(SeekFrom::End(0)).await?;
+            handle.write_all(&pq_codes_to_be_written).await?;
+            handle.flush().await?;
+        }
         Ok(())
     }
 
+    #[allow(dead_code)]
     pub async fn clear(&mut self) -> Result<(), std::io::Error>{
+        self.mapping = None;
         self.keys_handle.set_len(0).await?;
         self.values_handle.set_len(0).await?;
         self.keys_handle.seek(SeekFrom::Start(0)).await?;
         self.values_handle.seek(SeekFrom::Start(0)).await?;
+        let handles = [&mut self.timestamps_handle, &mut self.metadata_handle, &mut self.metadata_offsets_handle, &mut self.tombstones_handle, &mut self.external_ids_handle, &mut self.usage_handle, &mut self.expirations_handle].map(Some);
+        for handle in [self.int8_keys_handle.as_mut(), self.pq_codes_handle.as_mut()].into_iter().chain(handles).flatten() {
+            handle.set_len(0).await?;
+            handle.seek(SeekFrom::Start(0)).await?;
+        }
+        self.timestamps.clear();
+        self.metadata_offsets.clear();
+        self.deleted.clear();
+        self.deleted_count = 0;
+        self.external_ids.clear();
+        self.usage.clear();
+        self.usage_dirty = false;
+        self.expirations.clear();
         Ok(())
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct AlreadyInUse { name: String, ty: String }
-impl Display for AlreadyInUse {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{} {} already in use.", self.ty, self.name)
+/// Path file `name` of the bucket at `path` is written to during compaction.
+fn compaction_path(path: &Path, name: &str) -> PathBuf {
+    path.join(format!("{name}{COMPACTION_SUFFIX}"))
+}
+
+/// Copies rows of `row_size` bytes of file `from` that are to be kept to file `to`, making sure they reach the disk.
+fn copy_kept_rows(from: &Path, to: &Path, row_size: usize, keep: &[bool]) -> Result<(), std::io::Error> {
+    let mut reader = std::io::BufReader::new(std::fs::File::open(from)?);
+    let mut writer = std::io::BufWriter::new(std::fs::File::create(to)?);
+    let mut row = vec![0u8; row_size];
+    for &keep in keep {
+        reader.read_exact(&mut row)?;
+        if keep {
+            writer.write_all(&row)?;
+        }
+    }
+    writer.into_inner().map_err(|err| err.into_error())?.sync_all()
+}
+
+/// Writes `bytes` to file `path`, making sure they reach the disk.
+fn write_synced(path: &Path, bytes: &[u8]) -> Result<(), std::io::Error> {
+    let mut file = std::fs::File::create(path)?;
+    file.write_all(bytes)?;
+    file.sync_all()
+}
+
+/// Writes the names of the files rewritten by compaction of the bucket at `path`, which finishes the compaction.
+async fn write_compaction_marker(path: &Path, names: &[&str]) -> Result<(), StorageError> {
+    let marker = names.join("\n");
+    let path = path.join(COMPACTION_MARKER);
+    tokio::task::spawn_blocking(move || write_synced(&path, marker.as_bytes())).await.map_err(std::io::Error::other)??;
+    Ok(())
+}
+
+/// Finishes compaction of the bucket at `path` if all of its files were written, replacing the old files with them.
+/// Otherwise discards the files written by the interrupted compaction.
+async fn recover_compaction(path: &Path) -> Result<(), StorageError> {
+    let marker = path.join(COMPACTION_MARKER);
+    if marker.exists() {
+        for name in tokio::fs::read_to_string(&marker).await?.lines() {
+            let compacted = compaction_path(path, name);
+            // Files renamed before a crash are already in place.
+            if compacted.exists() {
+                tokio::fs::rename(compacted, path.join(name)).await?;
+            }
+        }
+        tokio::fs::remove_file(marker).await?;
+        return Ok(());
+    }
+    let mut entries = tokio::fs::read_dir(path).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        if entry.file_name().to_string_lossy().ends_with(COMPACTION_SUFFIX) {
+            tokio::fs::remove_file(entry.path()).await?;
+        }
     }
+    Ok(())
 }
 
-impl Error for AlreadyInUse {}
+/// Milliseconds from the Unix epoch to `time`, zero for earlier times.
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64)
+}
+
+/// Writes upper layers of the HNSW graph, replacing the previous ones at once.
+async fn write_hnsw_upper(path: &Path, upper: &UpperLayers) -> Result<(), StorageError> {
+    let bytes = bincode::serialize(upper).map_err(std::io::Error::other)?;
+    tokio::fs::write(path.join("hnsw.upper.tmp"), bytes).await?;
+    tokio::fs::rename(path.join("hnsw.upper.tmp"), path.join("hnsw.upper")).await?;
+    Ok(())
+}
+
+/// Exact keys of a memory-mapped bucket, converted to `f32` one at a time.
+struct MappedKeys<'a, T> {
+    keys: ArrayView2<'a, T>,
+}
+
+impl<T: StoredElement> Vectors for MappedKeys<'_, T> {
+    fn dot(&self, q: &[f32], row: usize) -> f32 {
+        q.iter().zip(self.keys.row(row)).map(|(a, b)| a * b.to_f32()).sum()
+    }
+
+    fn vector(&self, row: usize) -> Vec<f32> {
+        self.keys.row(row).iter().map(|x| x.to_f32()).collect()
+    }
+}
 
+/// Interprets flat keys and values as matrices with one entry per row, `key_width` and `value_width` elements wide.
+fn as_kv_views<'a, K, V>(keys: &'a [K], key_width: usize, values: &'a [V], value_width: usize) -> Result<(ArrayView2<'a, K>, ArrayView2<'a, V>), InvalidLayoutError> {
+    if !keys.len().is_multiple_of(key_width) || !values.len().is_multiple_of(value_width) || keys.len() / key_width != values.len() / value_width {
+        return Err(InvalidLayoutError);
+    }
+    let rows = keys.len() / key_width;
+    let keys = ArrayView2::from_shape((rows, key_width), keys).map_err(|_| InvalidLayoutError)?;
+    let values = ArrayView2::from_shape((rows, value_width), values).map_err(|_| InvalidLayoutError)?;
+    Ok((keys, values))
+}
+
+/// Tells the OS that `file` is going to be read sequentially from start to `len`.
+#[cfg(target_os = "linux")]
+fn advise_sequential(file: &std::fs::File, len: u64) {
+    use std::os::fd::AsRawFd;
+    // Only a hint, reads work the same if it fails.
+    unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, len as libc::off_t, libc::POSIX_FADV_SEQUENTIAL) };
+}
+
+#[cfg(not(target_os = "linux"))]
+fn advise_sequential(_file: &std::fs::File, _len: u64) {}
+
+/// Asks the OS to start reading `len` bytes of `file` from `offset` in background.
+#[cfg(target_os = "linux")]
+fn advise_will_need(file: &std::fs::File, offset: u64, len: u64) {
+    use std::os::fd::AsRawFd;
+    // Only a hint, reads work the same if it fails.
+    unsafe { libc::posix_fadvise(file.as_raw_fd(), offset as libc::off_t, len as libc::off_t, libc::POSIX_FADV_WILLNEED) };
+}
+
+#[cfg(not(target_os = "linux"))]
+fn advise_will_need(_file: &std::fs::File, _offset: u64, _len: u64) {}
+
+/// Fills `buf` with bytes of `file` from `offset` on, leaving the position of `file` as it is.
+#[cfg(unix)]
+fn read_exact_at(file: &std::fs::File, buf: &mut [u8], offset: u64) -> Result<(), std::io::Error> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+/// Fills `buf` with bytes of `file` from `offset` on. Moves the position of `file`, which is always sought before use.
+#[cfg(windows)]
+fn read_exact_at(file: &std::fs::File, mut buf: &mut [u8], mut offset: u64) -> Result<(), std::io::Error> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset) {
+            Ok(0) => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            Ok(read) => {
+                buf = &mut buf[read..];
+                offset += read as u64;
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// Fills `buf` with bytes of `file` from `offset` on. Moves the position of `file`, which is always sought before use.
+#[cfg(not(any(unix, windows)))]
+fn read_exact_at(mut file: &std::fs::File, buf: &mut [u8], offset: u64) -> Result<(), std::io::Error> {
+    use std::io::{Read, Seek};
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(buf)
+}
 
 #[derive(Debug, Clone)]
 pub struct AlreadyExists { name: String, ty: String }
 impl Display for AlreadyExists {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{} {} already in exists.", self.ty, self.name)
+        write!(f, "{} {} already exists.", self.ty, self.name)
     }
 }
 
@@ -166,58 +1561,107 @@ pub struct Database {
     data_directory: PathBuf,
     buckets: HashMap<Arc<str>, Bucket>,
     conf: DatabaseConfiguration,
+    /// Projection matrices of the current version, if any were set.
+    projection: Option<Projection>,
 }
 
 impl Database {
-    pub fn get_qkv_vec_size(&self) -> u32 {
-        self.conf.qkv_vec_size
+    pub fn get_key_size(&self) -> u32 {
+        self.conf.key_size
+    }
+
+    pub fn get_value_size(&self) -> u32 {
+        self.conf.value_size()
+    }
+
+    pub fn get_configuration(&self) -> &DatabaseConfiguration {
+        &self.conf
+    }
+
+    pub fn get_projection(&self) -> Option<&Projection> {
+        self.projection.as_ref()
+    }
+
+    /// Names of all buckets of the database in alphabetical order.
+    pub fn bucket_names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.buckets.keys().map(|k| k.as_ref()).collect();
+        names.sort_unstable();
+        names
     }
 }
 
 impl Database {
-    pub async fn from_disk(data_directory: PathBuf) -> Result<Database, std::io::Error> {
+    pub async fn from_disk(data_directory: PathBuf) -> Result<Database, StorageError> {
         let content = tokio::fs::read_to_string(data_directory.join("bucket_info.index")).await?;
-        let buf = tokio::fs::read(data_directory.join("conf.bc")).await?;
-        let conf = bincode::deserialize(&buf).expect(&format!("Configuration file of database {} is corrupted. Unable to initialize database.", data_directory.file_name().unwrap().to_str().unwrap()));
+        let conf = DatabaseConfiguration::from_disk(&data_directory).await?;
         let bucket_names: Vec<&str> = content.split("\n").filter(|x| !x.is_empty()).collect();
         let mut buckets: HashMap<Arc<str>, Bucket> = Default::default();
         for name in bucket_names {
-            buckets.insert(Arc::from(name), Bucket::from_disk(&data_directory.join(&name), &conf).await?);
+            buckets.insert(Arc::from(name), Bucket::from_disk(&data_directory.join(name), &conf).await?);
         }
+        let projection = match conf.projection_version {
+            0 => None,
+            version => {
+                let bytes = tokio::fs::read(data_directory.join(format!("projection.{version}.bin"))).await?;
+                Some(Projection::from_bytes(&bytes).ok_or_else(|| StorageError::CorruptedConfiguration(data_directory.display().to_string()))?)
+            }
+        };
         Ok(Self {
-            data_directory, buckets, conf
+            data_directory, buckets, conf, projection
         })
     }
 
-    pub async fn get_bucket(&mut self, name: &str) -> Result<Option<&mut Bucket>, AlreadyInUse> {
-        match self.buckets.get_mut(name) {
-            None => {Ok(None)}
-            Some(b) => {
-                Ok(Some(b))
-            }
-        }
+    /// Replaces projection matrices of the database, returning their version.
+    /// Matrices of previous versions are kept on disk.
+    pub async fn set_projection(&mut self, projection: Projection) -> Result<u32, StorageError> {
+        let version = self.conf.projection_version + 1;
+        tokio::fs::write(self.data_directory.join(format!("projection.{version}.bin")), projection.to_bytes()).await?;
+        // Configuration is written last, so the database never refers to matrices that were not fully written.
+        let conf = DatabaseConfiguration { projection_version: version, ..self.conf };
+        let conf_json = serde_json::to_string_pretty(&conf).map_err(std::io::Error::other)?;
+        tokio::fs::write(self.data_directory.join("conf.json"), conf_json).await?;
+        self.conf = conf;
+        self.projection = Some(projection);
+        Ok(version)
+    }
+
+    pub async fn get_bucket(&mut self, name: &str) -> Option<&mut Bucket> {
+        self.buckets.get_mut(name)
+    }
+
+    /// Removes deleted entries from bucket `name`, see [`Bucket::compact`]. Returns `None` if there is no such bucket.
+    pub async fn compact_bucket(&mut self, name: &str) -> Result<Option<Compaction>, StorageError> {
+        let Some(bucket) = self.buckets.get_mut(name) else {
+            return Ok(None);
+        };
+        let compaction = bucket.compact().await?;
+        *bucket = Bucket::from_disk(&self.data_directory.join(name), &self.conf).await?;
+        Ok(Some(compaction))
     }
 
-    pub async fn create_bucket(&mut self, name: &str) -> Result<(), AlreadyExists> {
-        if self.buckets.keys().find(|x| x.as_ref() == name).is_some() {
+    pub async fn create_bucket(&mut self, name: &str, bucket_configuration: BucketConfiguration) -> Result<(), StorageError> {
+        if self.buckets.keys().any(|x| x.as_ref() == name) {
             return Err(AlreadyExists {
-                name: "Bucket".to_string(),
-                ty: name.to_string(),
-            });
+                name: name.to_string(),
+                ty: "Bucket".to_string(),
+            }.into());
         }
-        self.buckets.insert(name.into(), Bucket::initialize(&self.data_directory.join(name), self.conf).await.unwrap());
-        tokio::fs::write(self.data_directory.join("bucket_info.index"), self.buckets.keys().map(|k| k.to_string()).collect::<Vec<String>>().join("\n")).await.unwrap();
+        let bucket = Bucket::initialize(&self.data_directory.join(name), self.conf, bucket_configuration).await?;
+        self.buckets.insert(name.into(), bucket);
+        tokio::fs::write(self.data_directory.join("bucket_info.index"), self.buckets.keys().map(|k| k.to_string()).collect::<Vec<String>>().join("\n")).await?;
         Ok(())
     }
 
-    pub async fn initialize(data_directory: &Path, database_configuration: DatabaseConfiguration) -> Result<Database, std::io::Error> {
+    pub async fn initialize(data_directory: &Path, database_configuration: DatabaseConfiguration) -> Result<Database, StorageError> {
+        let conf = serde_json::to_string_pretty(&database_configuration).map_err(std::io::Error::other)?;
         tokio::fs::create_dir_all(data_directory).await?;
         tokio::fs::write(data_directory.join("bucket_info.index"), []).await?;
-        tokio::fs::write(data_directory.join("conf.bc"), bincode::serialize(&database_configuration).unwrap()).await?;
+        tokio::fs::write(data_directory.join("conf.json"), conf).await?;
         Ok(Self {
             data_directory: data_directory.into(),
             buckets: Default::default(),
             conf: database_configuration,
+            projection: None,
         })
     }
 }
@@ -228,8 +1672,14 @@ pub struct Storage {
 }
 
 impl Storage {
-    pub async fn from_disk(data_directory: PathBuf) -> Result<Storage, std::io::Error> {
-        let content = tokio::fs::read_to_string(data_directory.join("db_info.index")).await?;
+    pub async fn from_disk(data_directory: PathBuf) -> Result<Storage, StorageError> {
+        let index = data_directory.join("db_info.index");
+        if !index.exists() {
+            // Fresh data directory, nothing to load yet.
+            tokio::fs::create_dir_all(&data_directory).await?;
+            tokio::fs::write(&index, []).await?;
+        }
+        let content = tokio::fs::read_to_string(index).await?;
         let database_names: Vec<&str> = content.split("\n").filter(|x| !x.is_empty()).collect();
         let mut databases: HashMap<Arc<str>, Database> = Default::default();
         for name in database_names {
@@ -241,23 +1691,157 @@ impl Storage {
         })
     }
 
-    pub async fn create_database(&mut self, name: &str, database_configuration: DatabaseConfiguration) -> Result<(), AlreadyExists> {
-        if self.databases.keys().find(|x| x.as_ref() == name).is_some() {
+    pub async fn create_database(&mut self, name: &str, database_configuration: DatabaseConfiguration) -> Result<(), StorageError> {
+        if self.databases.keys().any(|x| x.as_ref() == name) {
             return Err(AlreadyExists {
-                name: "Database".to_string(),
-                ty: name.to_string(),
-            });
+                name: name.to_string(),
+                ty: "Database".to_string(),
+            }.into());
         };
-        self.databases.insert(name.into(), Database::initialize(&self.data_directory.join(name), database_configuration).await.unwrap());
-        tokio::fs::write(self.data_directory.join("db_info.index"), self.databases.keys().map(|k| k.to_string()).collect::<Vec<String>>().join("\n")).await.unwrap();
+        let database = Database::initialize(&self.data_directory.join(name), database_configuration).await?;
+        self.databases.insert(name.into(), database);
+        tokio::fs::write(self.data_directory.join("db_info.index"), self.databases.keys().map(|k| k.to_string()).collect::<Vec<String>>().join("\n")).await?;
         Ok(())
     }
-    pub async fn get_database(&mut self, name: &str) -> Result<Option<&mut Database>, AlreadyInUse> {
-        match self.databases.get_mut(name) {
-            None => {Ok(None)}
-            Some(b) => {
-                Ok(Some(b))
-            }
-        }
+    pub async fn get_database(&mut self, name: &str) -> Option<&mut Database> {
+        self.databases.get_mut(name)
+    }
+
+    pub fn databases_mut(&mut self) -> impl Iterator<Item = (&str, &mut Database)> {
+        self.databases.iter_mut().map(|(name, database)| (name.as_ref(), database))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn database_configuration() -> DatabaseConfiguration {
+        DatabaseConfiguration { key_size: 2, value_size: None, dtype: DType::F32, score: Score::Dot, temperature: 1., heads: 1, projection_version: 0 }
+    }
+
+    /// Empty directory for a test bucket, removed first if a previous run left it behind.
+    fn bucket_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("qkv-db-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        path
     }
-}
\ No newline at end of file
+
+    fn entry(value: f32) -> (Vec<f32>, Vec<f32>) {
+        (vec![value, 0.], vec![value, value])
+    }
+
+    fn ids(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    /// Bucket of four entries, the middle two of which are deleted.
+    async fn bucket_with_deletions(path: &Path, conf: DatabaseConfiguration) -> Bucket {
+        let mut bucket = Bucket::initialize(path, conf, BucketConfiguration::default()).await.unwrap();
+        bucket.insert_kv(vec![entry(1.), entry(2.), entry(3.), entry(4.)], None, None).await.unwrap();
+        bucket.delete(&[1, 2]).await.unwrap();
+        bucket
+    }
+
+    /// Whether files of a compaction or its marker are left in the bucket directory.
+    fn has_compaction_files(path: &Path) -> bool {
+        std::fs::read_dir(path).unwrap().any(|entry| {
+            let name = entry.unwrap().file_name().to_string_lossy().into_owned();
+            name.ends_with(COMPACTION_SUFFIX) || name == COMPACTION_MARKER
+        })
+    }
+
+    #[tokio::test]
+    async fn compaction_interrupted_before_marker_is_discarded() {
+        let path = bucket_path("compaction-before-marker");
+        let conf = database_configuration();
+        let mut bucket = bucket_with_deletions(&path, conf).await;
+        bucket.write_compaction().await.unwrap();
+        drop(bucket);
+
+        let bucket = Bucket::from_disk(&path, &conf).await.unwrap();
+        assert!(!has_compaction_files(&path));
+        assert_eq!(bucket.rows().await.unwrap(), 4);
+        assert_eq!(bucket.deleted_entries(), 2);
+        let (_, values) = bucket.read_entries(&[0, 3]).await.unwrap();
+        assert_eq!(values.into_raw_vec(), vec![1., 1., 4., 4.]);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn compaction_interrupted_after_marker_is_finished() {
+        let path = bucket_path("compaction-after-marker");
+        let conf = database_configuration();
+        let mut bucket = bucket_with_deletions(&path, conf).await;
+        let (names, compaction) = bucket.write_compaction().await.unwrap();
+        assert_eq!((compaction.entries, compaction.previous_ids), (4, vec![0, 3]));
+        write_compaction_marker(&path, &names).await.unwrap();
+        // The first file already replaced the old one when the process crashed.
+        std::fs::rename(compaction_path(&path, names[0]), path.join(names[0])).unwrap();
+        drop(bucket);
+
+        let bucket = Bucket::from_disk(&path, &conf).await.unwrap();
+        assert!(!has_compaction_files(&path));
+        assert_eq!(bucket.rows().await.unwrap(), 2);
+        assert_eq!(bucket.deleted_entries(), 0);
+        let (keys, values) = bucket.read_entries(&[0, 1]).await.unwrap();
+        assert_eq!(keys.into_raw_vec(), vec![1., 0., 4., 0.]);
+        assert_eq!(values.into_raw_vec(), vec![1., 1., 4., 4.]);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn upsert_after_compaction_replaces_only_its_own_entry() {
+        let path = bucket_path("upsert-after-compaction");
+        let conf = database_configuration();
+        let mut bucket = Bucket::initialize(&path, conf, BucketConfiguration::default()).await.unwrap();
+        bucket.upsert_kv(vec![entry(1.), entry(2.), entry(3.)], None, ids(&["a", "b", "c"]), None).await.unwrap();
+
+        bucket.delete(&[1]).await.unwrap();
+        assert!(!bucket.external_ids.contains_key("b"));
+        assert_eq!(bucket.compact().await.unwrap().previous_ids, vec![0, 2]);
+        let mut bucket = Bucket::from_disk(&path, &conf).await.unwrap();
+        assert_eq!(bucket.external_ids, HashMap::from([("a".to_string(), 0), ("c".to_string(), 1)]));
+
+        let replaced = bucket.upsert_kv(vec![entry(4.)], None, ids(&["b"]), None).await.unwrap();
+        assert_eq!(replaced, 0);
+        assert_eq!(bucket.deleted_entries(), 0);
+        let (_, values) = bucket.read_entries(&[0, 1, 2]).await.unwrap();
+        assert_eq!(values.into_raw_vec(), vec![1., 1., 3., 3., 4., 4.]);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn expired_entries_are_removed_by_compaction() {
+        let path = bucket_path("expired-compaction");
+        let conf = database_configuration();
+        let mut bucket = Bucket::initialize(&path, conf, BucketConfiguration::default()).await.unwrap();
+        bucket.upsert_kv(vec![entry(1.)], None, ids(&["a"]), None).await.unwrap();
+        bucket.upsert_kv(vec![entry(2.), entry(3.)], None, ids(&["b", "c"]), Some(0.001)).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        assert_eq!(bucket.expire().await.unwrap(), 2);
+        assert!(bucket.needs_compaction());
+        assert_eq!(bucket.compact().await.unwrap().previous_ids, vec![0]);
+        let bucket = Bucket::from_disk(&path, &conf).await.unwrap();
+        assert_eq!(bucket.rows().await.unwrap(), 1);
+        assert_eq!((bucket.deleted_entries(), bucket.expired_entries()), (0, 0));
+        assert_eq!(bucket.external_ids, HashMap::from([("a".to_string(), 0)]));
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn usage_kept_in_memory_survives_compaction() {
+        let path = bucket_path("usage-compaction");
+        let conf = database_configuration();
+        let mut bucket = Bucket::initialize(&path, conf, BucketConfiguration::default()).await.unwrap();
+        bucket.insert_kv(vec![entry(1.), entry(2.), entry(3.)], None, None).await.unwrap();
+        bucket.record_usage(&[(2, 0.5), (2, 0.25)]);
+        bucket.delete(&[0]).await.unwrap();
+
+        bucket.compact().await.unwrap();
+        let bucket = Bucket::from_disk(&path, &conf).await.unwrap();
+        assert_eq!(bucket.usage.iter().map(|usage| usage.weight).collect::<Vec<f32>>(), vec![0., 0.75]);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+}